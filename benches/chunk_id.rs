@@ -0,0 +1,45 @@
+//! Compare hashing a chunk segment by segment with [`Hasher::chunk_id`].
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use epochfs::{Blake3Hasher, Hasher};
+use opendal::Buffer;
+
+const CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// The previous path feeding every `Bytes` of the buffer one by one.
+fn per_bytes(buf: &Buffer) -> String {
+    let mut state = Blake3Hasher.start();
+    for bs in buf.clone() {
+        state.update(&bs);
+    }
+    state.finish()
+}
+
+/// A chunk of `CHUNK_SIZE` bytes made of segments of `segment` bytes.
+fn segmented(segment: usize) -> Buffer {
+    let content: Vec<u8> = (0..CHUNK_SIZE).map(|i| (i * 31 % 251) as u8).collect();
+    content
+        .chunks(segment)
+        .map(Bytes::copy_from_slice)
+        .collect()
+}
+
+fn bench_chunk_id(c: &mut Criterion) {
+    let mut group = c.benchmark_group("chunk_id");
+    group.throughput(Throughput::Bytes(CHUNK_SIZE as u64));
+    for segment in [1024, 4 * 1024, 64 * 1024, CHUNK_SIZE] {
+        let buf = segmented(segment);
+        assert_eq!(per_bytes(&buf), Blake3Hasher.chunk_id(&buf));
+        group.bench_with_input(BenchmarkId::new("per_bytes", segment), &buf, |b, buf| {
+            b.iter(|| per_bytes(buf))
+        });
+        group.bench_with_input(BenchmarkId::new("chunk_id", segment), &buf, |b, buf| {
+            b.iter(|| Blake3Hasher.chunk_id(buf))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_chunk_id);
+criterion_main!(benches);
@@ -0,0 +1,12 @@
+/// The report of auditing objects in the chunk storage.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StorageAudit {
+    /// Valid chunks referenced by the file system.
+    pub referenced: Vec<String>,
+    /// Valid chunks that are not referenced by the file system.
+    pub unreferenced: Vec<String>,
+    /// Objects whose name is not a well-formed chunk id.
+    pub foreign: Vec<String>,
+    /// Objects whose content doesn't hash to their chunk id.
+    pub mismatched: Vec<String>,
+}
@@ -0,0 +1,74 @@
+use crate::{File, Fs};
+use anyhow::anyhow;
+use anyhow::Result;
+use opendal::Buffer;
+use tokio::runtime::Handle;
+
+/// A blocking wrapper of [`Fs`].
+///
+/// All async operations are driven by `block_on` on the given runtime handle,
+/// so it must not be used inside an async context of the same runtime.
+pub struct BlockingFs {
+    handle: Handle,
+    fs: Fs,
+}
+
+impl BlockingFs {
+    pub fn new(fs: Fs, handle: Handle) -> Self {
+        Self { handle, fs }
+    }
+
+    /// Consume the wrapper and return the inner [`Fs`].
+    pub fn into_inner(self) -> Fs {
+        self.fs
+    }
+
+    /// Read the whole content of the file at given path.
+    pub fn read(&self, path: &str) -> Result<Buffer> {
+        self.handle.block_on(self.fs.read(path))
+    }
+
+    /// Get the file at given path, returns error if not exists.
+    pub fn get(&self, path: &str) -> Result<File> {
+        self.fs
+            .stat(path)
+            .cloned()
+            .ok_or_else(|| anyhow!("file {path} not found"))
+    }
+
+    /// Get the file at given path, returns `None` if not exists.
+    pub fn stat(&self, path: &str) -> Option<&File> {
+        self.fs.stat(path)
+    }
+
+    /// List all files in the current snapshot, ordered by path.
+    pub fn list(&self) -> impl Iterator<Item = &File> {
+        self.fs.list()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::memory;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_read_from_sync_context() -> Result<()> {
+        let rt = tokio::runtime::Runtime::new()?;
+        let mut fs = rt.block_on(Fs::create(memory()))?;
+        let content = Buffer::from("hello, blocking");
+        let file = rt.block_on(fs.replace_file("a", content.clone()))?;
+        let expected = rt.block_on(fs.read("a"))?;
+
+        let fs = BlockingFs::new(fs, rt.handle().clone());
+        assert_eq!(fs.read("a")?.to_vec(), expected.to_vec());
+        assert_eq!(fs.read("a")?.to_vec(), content.to_vec());
+        assert_eq!(fs.get("a")?.chunks(), file.chunks());
+        assert_eq!(fs.stat("a").map(File::size), Some(content.len() as u64));
+        assert_eq!(fs.list().count(), 1);
+        assert!(fs.get("b").is_err());
+        assert!(fs.read("b").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+use opendal::Buffer;
+
+/// The strategy of splitting files into chunks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Chunking {
+    /// Split files at every chunk size.
+    #[default]
+    Fixed,
+    /// Split files at content defined boundaries found by a FastCDC style
+    /// rolling hash, so that edits only change chunks around them.
+    ///
+    /// Chunks are between `min` and `max` bytes, and `avg` bytes on average.
+    Cdc { min: usize, avg: usize, max: usize },
+}
+
+/// The resolved chunker used by writers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Chunker {
+    Fixed(usize),
+    Cdc {
+        min: usize,
+        avg: usize,
+        max: usize,
+        mask_s: u64,
+        mask_l: u64,
+    },
+}
+
+impl Chunker {
+    pub(crate) fn new(chunking: Chunking, chunk_size: usize) -> Self {
+        match chunking {
+            Chunking::Fixed => Chunker::Fixed(chunk_size),
+            Chunking::Cdc { min, avg, max } => {
+                let bits = avg.max(4).ilog2();
+                // Normalized chunking: harder to cut before `avg`, easier after.
+                Chunker::Cdc {
+                    min,
+                    avg,
+                    max,
+                    mask_s: !0u64 << (63 - bits),
+                    mask_l: !0u64 << (65 - bits),
+                }
+            }
+        }
+    }
+
+    /// The max size of a chunk.
+    pub(crate) fn max_size(&self) -> usize {
+        match *self {
+            Chunker::Fixed(size) => size,
+            Chunker::Cdc { max, .. } => max,
+        }
+    }
+
+    /// Find the end of the first chunk in `buf`.
+    ///
+    /// Returning `None` if more data is needed to decide the boundary.
+    pub(crate) fn cut(&self, buf: &Buffer) -> Option<usize> {
+        if let Chunker::Fixed(size) = *self {
+            return (buf.len() >= size).then_some(size);
+        }
+
+        let mut cutter = Cutter::new(*self);
+        let mut offset = 0;
+        for bs in buf.clone() {
+            if let Some(n) = cutter.feed(&bs) {
+                return Some(offset + n);
+            }
+            offset += bs.len();
+        }
+        None
+    }
+}
+
+/// The incremental state of finding the boundary of a chunk.
+pub(crate) struct Cutter {
+    chunker: Chunker,
+    pos: usize,
+    hash: u64,
+}
+
+impl Cutter {
+    pub(crate) fn new(chunker: Chunker) -> Self {
+        Self {
+            chunker,
+            pos: 0,
+            hash: 0,
+        }
+    }
+
+    /// Feed the next bytes of current chunk.
+    ///
+    /// Returning the number of bytes before the boundary if found, the
+    /// state is reset for the next chunk then.
+    pub(crate) fn feed(&mut self, data: &[u8]) -> Option<usize> {
+        let (min, avg, max, mask_s, mask_l) = match self.chunker {
+            Chunker::Fixed(size) => {
+                let n = size - self.pos;
+                if data.len() >= n {
+                    self.pos = 0;
+                    return Some(n);
+                }
+                self.pos += data.len();
+                return None;
+            }
+            Chunker::Cdc {
+                min,
+                avg,
+                max,
+                mask_s,
+                mask_l,
+            } => (min, avg, max, mask_s, mask_l),
+        };
+
+        for (i, &b) in data.iter().enumerate() {
+            self.pos += 1;
+            if self.pos <= min {
+                continue;
+            }
+            self.hash = (self.hash << 1).wrapping_add(GEAR[b as usize]);
+            let mask = if self.pos < avg { mask_s } else { mask_l };
+            if self.pos >= max || self.hash & mask == 0 {
+                self.pos = 0;
+                self.hash = 0;
+                return Some(i + 1);
+            }
+        }
+        None
+    }
+}
+
+/// The gear table of random values for the rolling hash.
+static GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64 with a fixed seed, chunk boundaries must never change.
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x6570_6f63_6866_7300;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
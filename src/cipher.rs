@@ -0,0 +1,74 @@
+use anyhow::anyhow;
+use anyhow::Result;
+use opendal::Buffer;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// The name of the cipher recorded in metadata.
+pub(crate) const CIPHER_NAME: &str = "chacha20-poly1305";
+
+/// The AEAD cipher of chunks, manifests and metadata.
+///
+/// Every object is stored as `nonce || ciphertext || tag` with a random
+/// nonce, and authenticated together with associated data naming the
+/// object, so that an object can't be swapped for another one sealed by the
+/// same key. Chunk ids are still calculated over the plaintext, so dedup keeps
+/// working and the same key produces the same ids on every machine, at the
+/// cost of revealing plaintext hashes through object names.
+pub(crate) struct Cipher {
+    key: LessSafeKey,
+    rng: SystemRandom,
+}
+
+impl Cipher {
+    /// The extra bytes of an encrypted object.
+    pub(crate) const OVERHEAD: usize = NONCE_LEN + 16;
+
+    pub(crate) fn new(key: &[u8; 32]) -> Self {
+        let key = UnboundKey::new(&CHACHA20_POLY1305, key).expect("key length must be valid");
+        Self {
+            key: LessSafeKey::new(key),
+            rng: SystemRandom::new(),
+        }
+    }
+
+    pub(crate) fn encrypt(&self, buf: Buffer, aad: &[u8]) -> Result<Buffer> {
+        let mut nonce = [0; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|_| anyhow!("failed to generate nonce"))?;
+
+        let mut content = buf.to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(aad),
+                &mut content,
+            )
+            .map_err(|_| anyhow!("failed to encrypt content"))?;
+
+        let mut sealed = Vec::with_capacity(NONCE_LEN + content.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&content);
+        Ok(sealed.into())
+    }
+
+    pub(crate) fn decrypt(&self, buf: Buffer, aad: &[u8]) -> Result<Buffer> {
+        let content = buf.to_vec();
+        if content.len() < Self::OVERHEAD {
+            return Err(anyhow!("encrypted content is too short"));
+        }
+        let (nonce, content) = content.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce)
+            .map_err(|_| anyhow!("invalid nonce of encrypted content"))?;
+
+        let mut content = content.to_vec();
+        let plain = self
+            .key
+            .open_in_place(nonce, Aad::from(aad), &mut content)
+            .map_err(|_| anyhow!("failed to decrypt content, the key may be wrong"))?;
+        let len = plain.len();
+        content.truncate(len);
+        Ok(content.into())
+    }
+}
@@ -0,0 +1,149 @@
+use anyhow::anyhow;
+use anyhow::Result;
+use bytes::Buf;
+use opendal::Buffer;
+
+/// The flag of encrypted chunks in the chunk header.
+pub(crate) const ENCRYPTED_FLAG: u8 = 0x80;
+
+/// The size of the sample compressed to probe whether a chunk is
+/// compressible.
+const PROBE_SIZE: usize = 64 * 1024;
+
+/// Chunks are stored as is if the sample doesn't shrink below this ratio.
+const PROBE_RATIO: f64 = 0.9;
+
+/// The codec used to compress chunk content.
+///
+/// Chunk ids are always hashed over the uncompressed content, so chunks are
+/// deduplicated no matter which codec they are written with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    /// Chunks are stored as is.
+    #[default]
+    None,
+    /// Chunks are compressed by zstd with the given level.
+    Zstd { level: i32 },
+}
+
+impl Codec {
+    /// Restore the codec recorded in metadata.
+    pub(crate) fn from_config(name: Option<&str>, level: i32) -> Result<Self> {
+        match name {
+            None => Ok(Codec::None),
+            Some("zstd") => Ok(Codec::Zstd { level }),
+            Some(name) => Err(anyhow!("unsupported compression codec {name}")),
+        }
+    }
+
+    /// The name recorded in metadata, `None` if not compressed.
+    pub(crate) fn name(&self) -> Option<&'static str> {
+        match self {
+            Codec::None => None,
+            Codec::Zstd { .. } => Some("zstd"),
+        }
+    }
+
+    /// The level recorded in metadata.
+    pub(crate) fn level(&self) -> i32 {
+        match self {
+            Codec::None => 0,
+            Codec::Zstd { level } => *level,
+        }
+    }
+
+    /// The tag of the codec in the chunk header.
+    pub(crate) fn tag(&self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Zstd { .. } => 1,
+        }
+    }
+
+    /// Check whether the content is worth compressing by compressing a
+    /// sample of it.
+    ///
+    /// Content no larger than the sample is always worth a try, since the
+    /// sample costs as much as the whole.
+    pub(crate) fn compressible(&self, buf: &Buffer) -> bool {
+        if *self == Codec::None {
+            return false;
+        }
+        if buf.len() <= PROBE_SIZE {
+            return true;
+        }
+        let start = (buf.len() - PROBE_SIZE) / 2;
+        let sample = buf.slice(start..start + PROBE_SIZE);
+        self.compress(&sample)
+            .is_ok_and(|compressed| (compressed.len() as f64) < PROBE_SIZE as f64 * PROBE_RATIO)
+    }
+
+    /// Compress the content into a single zstd frame.
+    pub(crate) fn compress(&self, buf: &Buffer) -> Result<Buffer> {
+        match self {
+            Codec::None => Ok(buf.clone()),
+            Codec::Zstd { level } => {
+                Ok(zstd::stream::encode_all(buf.clone().reader(), *level)?.into())
+            }
+        }
+    }
+
+    /// Decompress the content by the codec tag of its chunk header.
+    pub(crate) fn decompress(tag: u8, buf: Buffer) -> Result<Buffer> {
+        match tag {
+            0 => Ok(buf),
+            1 => Ok(zstd::stream::decode_all(buf.reader())
+                .map_err(|err| anyhow!("invalid zstd frame: {err}"))?
+                .into()),
+            tag => Err(anyhow!("unsupported codec tag {tag} of chunk header")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{noise, text};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_zstd_round_trip() -> Result<()> {
+        for data in [vec![], b"a".to_vec(), text(300_000), noise(100_000)] {
+            for level in [1, 3, 19] {
+                let codec = Codec::Zstd { level };
+                let compressed = codec.compress(&Buffer::from(data.clone()))?;
+                let decompressed = Codec::decompress(codec.tag(), compressed)?;
+                assert_eq!(decompressed.to_vec(), data);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_zstd_standard_frames() -> Result<()> {
+        // Frames written by other zstd encoders are readable.
+        let data = text(100_000);
+        let frame = zstd::bulk::compress(&data, 3)?;
+        let tag = Codec::Zstd { level: 3 }.tag();
+        assert_eq!(Codec::decompress(tag, frame.into())?.to_vec(), data);
+
+        let mut corrupted = zstd::bulk::compress(&data, 3)?;
+        corrupted.truncate(corrupted.len() / 2);
+        assert!(Codec::decompress(tag, corrupted.into()).is_err());
+        assert!(Codec::decompress(tag, Buffer::from("not a zstd frame")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_zstd_levels() -> Result<()> {
+        let data = Buffer::from(text(1 << 20));
+        let fast = Codec::Zstd { level: 1 }.compress(&data)?;
+        let best = Codec::Zstd { level: 19 }.compress(&data)?;
+        assert!(fast.len() < data.len() / 3);
+        assert!(best.len() < fast.len());
+
+        assert!(Codec::Zstd { level: 3 }.compressible(&data));
+        assert!(!Codec::Zstd { level: 3 }.compressible(&Buffer::from(noise(1 << 20))));
+        Ok(())
+    }
+}
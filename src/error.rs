@@ -0,0 +1,46 @@
+use std::fmt;
+
+/// Errors that callers may want to handle specially.
+///
+/// They are returned wrapped in [`anyhow::Error`], use `downcast_ref` to
+/// check for them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The writer is closed without writing any bytes while empty files
+    /// are not allowed.
+    EmptyFile { path: String },
+    /// The file system already exists.
+    AlreadyExists,
+    /// The version has been sealed and can't be committed on.
+    Sealed { version: usize },
+    /// The metadata kept being changed by other writers, and the commit
+    /// still conflicts after `attempts` merges.
+    CommitConflict { attempts: usize },
+    /// The file was changed by both us and another writer since the
+    /// manifest we loaded, so the commit can't be merged.
+    MergeConflict { path: String },
+    /// An upload of the writer failed, so its buffered bytes are lost and
+    /// it can't be written or closed anymore.
+    Poisoned { path: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::EmptyFile { path } => write!(f, "file {path} is empty"),
+            Error::AlreadyExists => write!(f, "file system already exists"),
+            Error::Sealed { version } => write!(f, "version {version} has been sealed"),
+            Error::CommitConflict { attempts } => {
+                write!(f, "commit still conflicts after {attempts} attempts")
+            }
+            Error::MergeConflict { path } => {
+                write!(f, "file {path} was changed by both us and another writer")
+            }
+            Error::Poisoned { path } => {
+                write!(f, "writer of file {path} is poisoned by a failed upload")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -1,81 +1,534 @@
+use std::fmt::{self, Debug, Formatter};
+use std::io;
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use std::{mem, sync::Arc};
 
-use crate::{fs::FsContext, specs::v1 as specs_v1};
+use crate::chunking::Chunker;
+use crate::specs::{v1 as specs_v1, v2 as specs_v2};
+use crate::{fs::FsContext, Error};
+use anyhow::anyhow;
 use anyhow::Result;
-use bytes::Buf as _;
+use bincode::{Decode, Encode};
+use bytes::{Buf as _, Bytes};
 use chrono::{DateTime, Utc};
-use futures::StreamExt as _;
+use futures::future::BoxFuture;
+use futures::{ready, stream, FutureExt as _, StreamExt as _, TryStreamExt as _};
 use opendal::{Buffer, BufferStream};
+use tokio::io::AsyncWrite;
 
 /// Use 8MiB as the default chunk size.
 pub const DEFAULT_CHUNK_SIZE: usize = 8 * 1024 * 1024;
 
-#[derive(Debug, Clone)]
+/// The policy of storing small files inline in the manifest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InlinePolicy {
+    /// Always store files as chunks.
+    #[default]
+    Never,
+    /// Store files smaller than the given bytes inline.
+    BelowBytes(usize),
+    /// Store files inline as long as they fit in one chunk.
+    Always,
+}
+
+/// A file in the file system.
+///
+/// An empty file has no chunks, no inline content and `size == 0`. It's
+/// read without touching the storage, and always valid for verify.
+#[derive(Debug, Clone, PartialEq)]
 pub struct File {
     path: String,
     chunks: Vec<String>,
+    /// The size of every chunk, in the same order of `chunks`.
+    chunk_sizes: Vec<u64>,
+    /// The stored size of every chunk, empty if unknown.
+    stored_sizes: Vec<u64>,
+    /// The content of the file if it's stored inline.
+    inline: Option<Bytes>,
+    /// The logical format of the file, like `parquet` or `json`.
+    format: Option<String>,
 
     size: u64,
     last_modified: DateTime<Utc>,
+
+    offsets: OffsetCache,
+}
+
+/// The start offset of every chunk computed on first use, shared by clones
+/// of the file.
+///
+/// It's derived from chunk sizes, so it's ignored by comparisons.
+#[derive(Clone, Default)]
+struct OffsetCache(Arc<OnceLock<Vec<u64>>>);
+
+impl PartialEq for OffsetCache {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Debug for OffsetCache {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("OffsetCache")
+    }
 }
 
 impl File {
+    pub(crate) fn new(
+        path: String,
+        chunks: Vec<String>,
+        chunk_sizes: Vec<u64>,
+        stored_sizes: Vec<u64>,
+        last_modified: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            path,
+            chunks,
+            size: chunk_sizes.iter().sum(),
+            chunk_sizes,
+            stored_sizes,
+            inline: None,
+            format: None,
+            last_modified,
+            offsets: OffsetCache::default(),
+        }
+    }
+
     pub fn path(&self) -> &str {
         &self.path
     }
+
+    /// The logical format of the file, `None` if not set.
+    pub fn format(&self) -> Option<&str> {
+        self.format.as_deref()
+    }
+
+    /// The ids of chunks of the file in order, empty for inline files.
+    pub fn chunks(&self) -> &[String] {
+        &self.chunks
+    }
+
+    pub(crate) fn chunk_sizes(&self) -> &[u64] {
+        &self.chunk_sizes
+    }
+
+    /// The start offset of every chunk, with the sum of chunk sizes at the
+    /// end.
+    ///
+    /// Computed once and cached, clones of the file share the cache.
+    pub(crate) fn offsets(&self) -> &[u64] {
+        self.offsets.0.get_or_init(|| {
+            let mut offsets = Vec::with_capacity(self.chunk_sizes.len() + 1);
+            let mut offset = 0;
+            offsets.push(offset);
+            for size in &self.chunk_sizes {
+                offset += size;
+                offsets.push(offset);
+            }
+            offsets
+        })
+    }
+
+    /// The stored size of every chunk, empty if unknown.
+    pub(crate) fn stored_sizes(&self) -> &[u64] {
+        &self.stored_sizes
+    }
+
+    pub(crate) fn inline(&self) -> Option<&Bytes> {
+        self.inline.as_ref()
+    }
+
+    /// The size of the file in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The size of chunk objects of the file in the storage.
+    ///
+    /// It differs from [`File::size`] if chunks are compressed or encrypted,
+    /// and is `0` for inline files. Returning `None` if chunks were written
+    /// before stored sizes were recorded.
+    pub fn stored_size(&self) -> Option<u64> {
+        if self.stored_sizes.len() != self.chunks.len() {
+            return None;
+        }
+        Some(self.stored_sizes.iter().sum())
+    }
+
+    /// The last modified time of the file.
+    pub fn last_modified(&self) -> DateTime<Utc> {
+        self.last_modified
+    }
+
+    /// Replace the stored sizes of chunks, like after copying them into
+    /// another file system.
+    pub(crate) fn with_stored_sizes(mut self, stored_sizes: Vec<u64>) -> Self {
+        self.stored_sizes = stored_sizes;
+        self
+    }
+
+    /// Move the file to another path.
+    pub(crate) fn with_path(mut self, path: String) -> Self {
+        self.path = path;
+        self
+    }
+
+    /// Check that the size matches the sum of chunk sizes.
+    ///
+    /// If `repair` is true, the size will be corrected instead of returning
+    /// an error. Inline files are not checked.
+    pub(crate) fn check_size(&mut self, repair: bool) -> Result<()> {
+        if self.inline.is_some() {
+            return Ok(());
+        }
+        if self.chunk_sizes.len() != self.chunks.len() {
+            return Err(anyhow!(
+                "file {} has {} chunks but {} chunk sizes",
+                self.path,
+                self.chunks.len(),
+                self.chunk_sizes.len()
+            ));
+        }
+        if !self.stored_sizes.is_empty() && self.stored_sizes.len() != self.chunks.len() {
+            return Err(anyhow!(
+                "file {} has {} chunks but {} stored sizes",
+                self.path,
+                self.chunks.len(),
+                self.stored_sizes.len()
+            ));
+        }
+        let expected: u64 = self.chunk_sizes.iter().sum();
+        if self.size == expected {
+            return Ok(());
+        }
+        if !repair {
+            return Err(anyhow!(
+                "file {} has size {} but chunks sum to {expected}",
+                self.path,
+                self.size
+            ));
+        }
+        log::warn!(
+            "file {} has size {} but chunks sum to {expected}, repaired",
+            self.path,
+            self.size
+        );
+        self.size = expected;
+        Ok(())
+    }
+
+    pub(crate) fn set_last_modified(&mut self, last_modified: DateTime<Utc>) {
+        self.last_modified = last_modified;
+    }
 }
 
 impl From<specs_v1::File> for File {
     fn from(value: specs_v1::File) -> Self {
+        specs_v2::File {
+            path: value.path,
+            chunks: value.chunks,
+            chunk_sizes: vec![],
+            stored_sizes: vec![],
+            inline: None,
+            format: None,
+            size: value.size,
+            last_modified: value.last_modified,
+        }
+        .into()
+    }
+}
+
+impl From<specs_v2::File> for File {
+    fn from(value: specs_v2::File) -> Self {
+        // Only files written before chunk sizes were recorded miss them, and
+        // they were always split by the default chunk size.
+        let chunk_sizes = if value.chunk_sizes.is_empty() {
+            fixed_chunk_sizes(value.size, value.chunks.len(), DEFAULT_CHUNK_SIZE as u64)
+        } else {
+            value.chunk_sizes
+        };
+
         Self {
             path: value.path,
             chunks: value.chunks,
+            chunk_sizes,
+            stored_sizes: value.stored_sizes,
+            inline: value.inline.map(Bytes::from),
+            format: value.format,
             size: value.size,
-            last_modified: DateTime::from_timestamp(value.last_modified as i64, 0).unwrap(),
+            last_modified: decode_timestamp(value.last_modified),
+            offsets: OffsetCache::default(),
         }
     }
 }
 
-impl From<File> for specs_v1::File {
-    fn from(value: File) -> specs_v1::File {
-        specs_v1::File {
+/// The sizes of `n` chunks of a file with `size` bytes split by a fixed
+/// `chunk_size`.
+pub(crate) fn fixed_chunk_sizes(size: u64, n: usize, chunk_size: u64) -> Vec<u64> {
+    if n == 0 {
+        return vec![];
+    }
+    let last = size.saturating_sub(chunk_size * (n as u64 - 1));
+    let mut sizes = vec![chunk_size; n - 1];
+    sizes.push(last);
+    sizes
+}
+
+/// Decode the timestamp stored in specs.
+///
+/// Out of range timestamps are clamped to the unix epoch instead of
+/// panicking, so that a corrupted manifest can still be loaded.
+fn decode_timestamp(ts: u64) -> DateTime<Utc> {
+    i64::try_from(ts)
+        .ok()
+        .and_then(|ts| DateTime::from_timestamp(ts, 0))
+        .unwrap_or_else(|| {
+            log::warn!("timestamp {ts} is out of range, clamped to the unix epoch");
+            DateTime::UNIX_EPOCH
+        })
+}
+
+impl From<File> for specs_v2::File {
+    fn from(value: File) -> specs_v2::File {
+        specs_v2::File {
             path: value.path,
             chunks: value.chunks,
+            chunk_sizes: value.chunk_sizes,
+            stored_sizes: value.stored_sizes,
+            inline: value.inline.map(|bs| bs.to_vec()),
+            format: value.format,
             size: value.size,
-            last_modified: value.last_modified.timestamp() as u64,
+            last_modified: value.last_modified.timestamp().max(0) as u64,
         }
     }
 }
 
+/// The result of closing a [`FileWriter`].
+#[derive(Debug, Clone)]
+pub struct CloseResult {
+    pub file: File,
+    /// The number of chunks newly uploaded by this writer.
+    pub new_chunks: usize,
+    /// The number of chunks that already exist in the storage.
+    pub dedup_chunks: usize,
+    /// The pin id of the file if requested by [`FileWriter::with_pin`].
+    ///
+    /// It can be resolved by [`crate::Fs::read_by_id`] later.
+    pub pin: Option<String>,
+}
+
+/// The checkpoint of a [`FileWriter`] that can be resumed later.
+///
+/// Only flushed chunks are recorded, the buffered tail is discarded. Callers
+/// should continue writing from `total_size` after resuming.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct WriterState {
+    pub chunks: Vec<String>,
+    pub chunk_sizes: Vec<u64>,
+    /// The stored size of every chunk, empty if unknown.
+    pub stored_sizes: Vec<u64>,
+    pub total_size: u64,
+}
+
+impl WriterState {
+    /// Encode the state into bytes.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(bincode::encode_to_vec(self, bincode::config::standard())?)
+    }
+
+    /// Decode the state from bytes.
+    pub fn from_bytes(bs: &[u8]) -> Result<Self> {
+        let (state, _) = bincode::decode_from_slice(bs, bincode::config::standard())?;
+        Ok(state)
+    }
+}
+
 pub struct FileWriter {
     ctx: Arc<FsContext>,
     path: String,
+    inline_policy: InlinePolicy,
+    allow_empty: bool,
+    format: Option<String>,
+    pin: bool,
+    chunker: Chunker,
+    idle_flush: Option<Duration>,
+    last_write: Instant,
 
     total_size: u64,
     chunks: Vec<String>,
+    chunk_sizes: Vec<u64>,
+    /// The stored size of every chunk, it's shorter than `chunks` if some
+    /// stored sizes are unknown.
+    stored_sizes: Vec<u64>,
+    new_chunks: usize,
+    dedup_chunks: usize,
 
     buf_size: usize,
     buf: Vec<Buffer>,
+    /// The upload started by [`AsyncWrite`] that hasn't finished yet.
+    inflight: Option<BoxFuture<'static, Result<Vec<UploadedChunk>>>>,
+    /// Whether an upload has failed, see [`Error::Poisoned`].
+    poisoned: bool,
 }
 
 impl FileWriter {
     pub fn new(ctx: Arc<FsContext>, path: String) -> Self {
         Self {
+            inline_policy: ctx.inline_policy(),
+            allow_empty: true,
+            format: None,
+            pin: false,
+            chunker: ctx.chunker(),
+            idle_flush: None,
+            last_write: Instant::now(),
             ctx,
             path,
 
             total_size: 0,
             chunks: vec![],
+            chunk_sizes: vec![],
+            stored_sizes: vec![],
+            new_chunks: 0,
+            dedup_chunks: 0,
             buf_size: 0,
             buf: vec![],
+            inflight: None,
+            poisoned: false,
         }
     }
 
+    /// Resume a writer from the checkpoint.
+    ///
+    /// Chunks in the checkpoint are reused without uploading again.
+    pub fn resume(ctx: Arc<FsContext>, path: String, state: WriterState) -> Self {
+        let mut w = Self::new(ctx, path);
+        w.chunks = state.chunks;
+        w.chunk_sizes = state.chunk_sizes;
+        w.stored_sizes = state.stored_sizes;
+        w.total_size = state.total_size;
+        w
+    }
+
+    /// Create a writer appending to the existing file.
+    ///
+    /// Full chunks of the file are reused as is. The tail chunk is read back
+    /// into the buffer if it's short, so that chunk boundaries stay aligned
+    /// after appending.
+    pub(crate) async fn append(ctx: Arc<FsContext>, file: &File) -> Result<Self> {
+        let mut w = Self::new(ctx, file.path.clone());
+        w.format = file.format.clone();
+        if let Some(inline) = &file.inline {
+            w.buf_size = inline.len();
+            w.buf.push(Buffer::from(inline.clone()));
+            return Ok(w);
+        }
+
+        w.chunks = file.chunks.clone();
+        w.chunk_sizes = file.chunk_sizes.clone();
+        w.stored_sizes = file.stored_sizes.clone();
+        w.total_size = file.size;
+        if let Some(&tail_size) = w.chunk_sizes.last() {
+            if (tail_size as usize) < w.chunker.max_size() {
+                let tail_id = w.chunks.pop().expect("chunks must match chunk sizes");
+                w.chunk_sizes.pop();
+                if w.stored_sizes.len() > w.chunks.len() {
+                    w.stored_sizes.pop();
+                }
+                w.total_size -= tail_size;
+
+                let tail = w.ctx.read_chunk(&tail_id).await?;
+                w.buf_size = tail.len();
+                w.buf.push(tail);
+            }
+        }
+        Ok(w)
+    }
+
+    /// Override the inline policy of the file system for this writer.
+    pub fn with_inline_policy(mut self, policy: InlinePolicy) -> Self {
+        self.inline_policy = policy;
+        self
+    }
+
+    /// Set whether closing the writer without writing any bytes is allowed.
+    ///
+    /// If not allowed, `close` returns [`Error::EmptyFile`]. Default to `true`.
+    pub fn with_allow_empty(mut self, allow_empty: bool) -> Self {
+        self.allow_empty = allow_empty;
+        self
+    }
+
+    /// Set the logical format of the file, like `parquet` or `json`.
+    pub fn with_format(mut self, format: &str) -> Self {
+        self.format = Some(format.to_string());
+        self
+    }
+
+    /// Set whether to write a pin for the file while closing.
+    ///
+    /// The pin is a chunk holding the file entry itself, so its id addresses
+    /// the exact content independent of the manifest. Pins are kept by gc
+    /// until [`Fs::unpin`](crate::Fs::unpin). Default to `false`.
+    pub fn with_pin(mut self, pin: bool) -> Self {
+        self.pin = pin;
+        self
+    }
+
+    /// Flush the buffered tail as a short chunk once the writer has been
+    /// idle for `interval`.
+    ///
+    /// The writer doesn't own a timer, callers should call
+    /// [`FileWriter::flush_if_idle`] periodically. Idle time is also checked
+    /// before every `write`. Short chunks are kept as is, so later data starts
+    /// a new chunk. Default to disabled.
+    pub fn with_idle_flush(mut self, interval: Duration) -> Self {
+        self.idle_flush = Some(interval);
+        self
+    }
+
+    /// Flush the buffered tail if the writer has been idle long enough.
+    ///
+    /// Returning `true` if a chunk has been flushed, the flushed data is
+    /// recorded in [`FileWriter::checkpoint`].
+    pub async fn flush_if_idle(&mut self) -> Result<bool> {
+        let Some(interval) = self.idle_flush else {
+            return Ok(false);
+        };
+        if self.buf_size == 0 || self.last_write.elapsed() < interval {
+            return Ok(false);
+        }
+        self.flush(true).await?;
+        Ok(true)
+    }
+
+    /// Take a checkpoint of the flushed chunks.
+    pub fn checkpoint(&self) -> WriterState {
+        WriterState {
+            chunks: self.chunks.clone(),
+            chunk_sizes: self.chunk_sizes.clone(),
+            stored_sizes: self.known_stored_sizes(),
+            total_size: self.total_size,
+        }
+    }
+
+    /// Write bytes into the writer.
+    ///
+    /// If an upload fails, the writer is poisoned and every following call
+    /// returns [`Error::Poisoned`]. Chunks recorded by
+    /// [`FileWriter::checkpoint`] are still valid to resume from.
     pub async fn write(&mut self, buf: Buffer) -> Result<()> {
+        self.check_poisoned()?;
+        self.flush_if_idle().await?;
+        self.last_write = Instant::now();
+
         self.buf_size += buf.len();
         self.buf.push(buf);
 
-        if self.buf_size >= DEFAULT_CHUNK_SIZE {
+        // Buffer enough chunks to keep concurrent uploads busy.
+        if self.buf_size >= self.chunker.max_size() * self.ctx.upload_concurrency() {
             self.flush(false).await?;
         }
 
@@ -89,45 +542,582 @@ impl FileWriter {
         Ok(())
     }
 
-    pub async fn close(&mut self) -> Result<File> {
-        self.flush(true).await?;
-        Ok(File {
+    pub async fn close(&mut self) -> Result<CloseResult> {
+        self.check_poisoned()?;
+        self.finish_inflight().await?;
+        if !self.allow_empty && self.total_size == 0 && self.buf_size == 0 {
+            return Err(Error::EmptyFile {
+                path: self.path.clone(),
+            }
+            .into());
+        }
+
+        let inline = if self.should_inline() {
+            let buf: Buffer = self.buf.drain(..).flatten().collect();
+            self.total_size += mem::take(&mut self.buf_size) as u64;
+            Some(buf.to_bytes())
+        } else {
+            self.flush(true).await?;
+            None
+        };
+
+        let stored_sizes = self.known_stored_sizes();
+        self.stored_sizes.clear();
+        let file = File {
             path: self.path.clone(),
             chunks: mem::take(&mut self.chunks),
+            chunk_sizes: mem::take(&mut self.chunk_sizes),
+            stored_sizes,
+            inline,
+            format: self.format.clone(),
             size: self.total_size,
             last_modified: Utc::now(),
+            offsets: OffsetCache::default(),
+        };
+        let pin = if self.pin {
+            Some(self.ctx.write_pin(&file).await?)
+        } else {
+            None
+        };
+        Ok(CloseResult {
+            file,
+            new_chunks: mem::take(&mut self.new_chunks),
+            dedup_chunks: mem::take(&mut self.dedup_chunks),
+            pin,
         })
     }
 
+    /// The stored sizes if all of them are known, otherwise empty.
+    fn known_stored_sizes(&self) -> Vec<u64> {
+        if self.stored_sizes.len() == self.chunks.len() {
+            self.stored_sizes.clone()
+        } else {
+            vec![]
+        }
+    }
+
+    /// Check whether the file should be stored inline while closing.
+    ///
+    /// Only files that never flushed a chunk could be inlined, empty files
+    /// are never inlined so that they have a single representation.
+    fn should_inline(&self) -> bool {
+        if self.buf_size == 0 || !self.chunks.is_empty() || self.buf_size >= self.chunker.max_size()
+        {
+            return false;
+        }
+        match self.inline_policy {
+            InlinePolicy::Never => false,
+            InlinePolicy::BelowBytes(n) => self.buf_size < n,
+            InlinePolicy::Always => true,
+        }
+    }
+
     /// Flush the buffer to the file system.
     ///
     /// If `finish` is true, it means that this is the last flush,
     /// it will flush all buffers no matter it's larger than chunk_size or not.
+    ///
+    /// Chunks are uploaded concurrently while recorded in file order.
     async fn flush(&mut self, finish: bool) -> Result<()> {
+        self.check_poisoned()?;
+        self.finish_inflight().await?;
+        let pending = self.take_chunks(finish);
+        let uploaded = upload_chunks(self.ctx.clone(), pending).await;
+        self.record_chunks(uploaded)
+    }
+
+    /// Wait for the upload started by [`AsyncWrite`] to finish.
+    async fn finish_inflight(&mut self) -> Result<()> {
+        if let Some(fut) = self.inflight.take() {
+            let uploaded = fut.await;
+            self.record_chunks(uploaded)?;
+        }
+        Ok(())
+    }
+
+    /// Return [`Error::Poisoned`] if an upload has failed.
+    fn check_poisoned(&self) -> Result<()> {
+        if self.poisoned {
+            return Err(Error::Poisoned {
+                path: self.path.clone(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Take chunks to upload out of the buffer.
+    ///
+    /// Only full chunks are taken unless `finish` is true.
+    fn take_chunks(&mut self, finish: bool) -> Vec<Buffer> {
         let mut buf: Buffer = self.buf.drain(..).flatten().collect();
 
-        while self.buf_size >= DEFAULT_CHUNK_SIZE {
-            let to_write = buf.slice(..DEFAULT_CHUNK_SIZE);
-            let chunk_id = self.ctx.write_chunk(to_write).await?;
-            buf.advance(DEFAULT_CHUNK_SIZE);
-            self.buf_size -= DEFAULT_CHUNK_SIZE;
-            self.total_size += DEFAULT_CHUNK_SIZE as u64;
+        let mut pending = Vec::new();
+        while let Some(chunk_size) = self.chunker.cut(&buf) {
+            pending.push(buf.slice(..chunk_size));
+            buf.advance(chunk_size);
+            self.buf_size -= chunk_size;
+        }
+        if self.buf_size > 0 {
+            if finish {
+                pending.push(buf);
+                self.buf_size = 0;
+            } else {
+                self.buf.push(buf);
+            }
+        }
+        pending
+    }
+
+    /// Record uploaded chunks in file order.
+    ///
+    /// Chunks taken out of the buffer are lost if the upload failed, so the
+    /// writer is poisoned then.
+    fn record_chunks(&mut self, uploaded: Result<Vec<UploadedChunk>>) -> Result<()> {
+        let uploaded = uploaded.inspect_err(|_| self.poisoned = true)?;
+        for (chunk_id, is_new, size, stored_size) in uploaded {
+            if is_new {
+                self.new_chunks += 1;
+            } else {
+                self.dedup_chunks += 1;
+            }
+            self.total_size += size;
             self.chunks.push(chunk_id);
+            self.chunk_sizes.push(size);
+            self.stored_sizes.push(stored_size);
         }
+        Ok(())
+    }
 
-        if self.buf_size == 0 {
-            return Ok(());
+    /// Poll the in-flight upload started by [`AsyncWrite`] until it's done.
+    fn poll_inflight(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(fut) = self.inflight.as_mut() {
+            let res = ready!(fut.as_mut().poll(cx));
+            self.inflight = None;
+            self.record_chunks(res).map_err(io::Error::other)?;
         }
+        Poll::Ready(self.check_poisoned().map_err(io::Error::other))
+    }
 
-        if finish {
-            let chunk_id = self.ctx.write_chunk(buf).await?;
-            self.total_size += self.buf_size as u64;
-            self.buf_size = 0;
-            self.chunks.push(chunk_id);
-        } else {
-            self.buf.push(buf);
+    /// Start uploading chunks taken from the buffer in background.
+    fn start_upload(&mut self, finish: bool) -> bool {
+        let pending = self.take_chunks(finish);
+        if pending.is_empty() {
+            return false;
+        }
+        self.inflight = Some(upload_chunks(self.ctx.clone(), pending).boxed());
+        true
+    }
+}
+
+/// The chunk id, whether it's newly uploaded, the size and the stored size
+/// of a chunk.
+type UploadedChunk = (String, bool, u64, u64);
+
+/// Upload chunks concurrently, returning them in the input order.
+async fn upload_chunks(ctx: Arc<FsContext>, pending: Vec<Buffer>) -> Result<Vec<UploadedChunk>> {
+    let ctx = &ctx;
+    stream::iter(pending)
+        .map(|buf| async move {
+            let size = buf.len() as u64;
+            let (chunk_id, is_new, stored_size) = ctx.upload_chunk(buf).await?;
+            Ok::<_, anyhow::Error>((chunk_id, is_new, size, stored_size))
+        })
+        .buffered(ctx.upload_concurrency())
+        .try_collect()
+        .await
+}
+
+/// Write bytes through [`AsyncWrite`], the explicit API keeps working
+/// alongside it.
+///
+/// `poll_shutdown` uploads all buffered bytes unless the file will be stored
+/// inline, [`FileWriter::close`] is still required to get the file.
+impl AsyncWrite for FileWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_inflight(cx))?;
+
+        this.last_write = Instant::now();
+        this.buf_size += buf.len();
+        this.buf.push(Buffer::from(buf.to_vec()));
+
+        if this.buf_size >= this.chunker.max_size() * this.ctx.upload_concurrency() {
+            this.start_upload(false);
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            ready!(this.poll_inflight(cx))?;
+            if !this.start_upload(false) {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            ready!(this.poll_inflight(cx))?;
+            if this.should_inline() || !this.start_upload(true) {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{memory, noise, text, MemoryChunkStore};
+    use crate::{Blake3Hasher, Chunking, Fs, Hasher};
+    use pretty_assertions::assert_eq;
+
+    async fn write_file(fs: &Fs, path: &str, content: &[u8]) -> Result<CloseResult> {
+        let mut writer = fs.new_file_writer(path);
+        writer.write(Buffer::from(content.to_vec())).await?;
+        writer.close().await
+    }
+
+    #[tokio::test]
+    async fn test_dedup_chunks() -> Result<()> {
+        let fs = Fs::builder(memory()).chunk_size(1024).build().await?;
+        let content = noise(4096);
+        let first = write_file(&fs, "a", &content).await?;
+        assert_eq!((first.new_chunks, first.dedup_chunks), (4, 0));
+
+        let mut shared = content[..2048].to_vec();
+        shared.extend_from_slice(&noise(4096 + 2048)[4096..]);
+        let second = write_file(&fs, "b", &shared).await?;
+        assert_eq!((second.new_chunks, second.dedup_chunks), (2, 2));
+        assert_eq!(first.file.chunks()[..2], second.file.chunks()[..2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_absurd_timestamp() {
+        for ts in [u64::MAX, i64::MAX as u64] {
+            let file: File = specs_v1::File {
+                path: "a".to_string(),
+                chunks: vec![],
+                size: 0,
+                last_modified: ts,
+            }
+            .into();
+            assert_eq!(file.last_modified(), DateTime::UNIX_EPOCH);
         }
 
+        let before_epoch = DateTime::from_timestamp(-1, 0).unwrap();
+        let mut file: File = specs_v1::File {
+            path: "a".to_string(),
+            chunks: vec![],
+            size: 0,
+            last_modified: 1_700_000_000,
+        }
+        .into();
+        assert_eq!(file.last_modified().timestamp(), 1_700_000_000);
+        file.set_last_modified(before_epoch);
+        assert_eq!(specs_v2::File::from(file).last_modified, 0);
+    }
+
+    #[tokio::test]
+    async fn test_resume_from_checkpoint() -> Result<()> {
+        let fs = Fs::builder(memory()).chunk_size(1024).build().await?;
+        let content = noise(10_000);
+        let expected = write_file(&fs, "expected", &content).await?.file;
+
+        let fs = Fs::builder(memory()).chunk_size(1024).build().await?;
+        let mut writer = fs.new_file_writer("a");
+        for part in content[..6000].chunks(1000) {
+            writer.write(Buffer::from(part.to_vec())).await?;
+        }
+        let state = WriterState::from_bytes(&writer.checkpoint().to_bytes()?)?;
+        drop(writer);
+        assert!(!state.chunks.is_empty());
+
+        let mut writer = fs.resume_file_writer("a", state.clone());
+        let rest = content[state.total_size as usize..].to_vec();
+        writer.write(Buffer::from(rest)).await?;
+        let resumed = writer.close().await?;
+        assert_eq!(resumed.file.chunks(), expected.chunks());
+        assert_eq!(resumed.file.size(), expected.size());
+        // Chunks in the checkpoint are not uploaded again.
+        assert_eq!(
+            resumed.new_chunks,
+            expected.chunks().len() - state.chunks.len()
+        );
+        assert_eq!(resumed.dedup_chunks, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_inline_policies() -> Result<()> {
+        let fs = Fs::builder(memory()).chunk_size(1024).build().await?;
+        let small = noise(100);
+        for (policy, inline) in [
+            (InlinePolicy::Never, false),
+            (InlinePolicy::BelowBytes(200), true),
+            (InlinePolicy::BelowBytes(50), false),
+            (InlinePolicy::Always, true),
+        ] {
+            let mut writer = fs.new_file_writer("a").with_inline_policy(policy);
+            writer.write(Buffer::from(small.clone())).await?;
+            let file = writer.close().await?.file;
+            assert_eq!(file.inline().is_some(), inline, "{policy:?}");
+            assert_eq!(file.chunks().len(), usize::from(!inline), "{policy:?}");
+            assert_eq!(file.size(), 100);
+        }
+
+        // Files larger than one chunk are never inlined.
+        let mut writer = fs
+            .new_file_writer("b")
+            .with_inline_policy(InlinePolicy::Always);
+        writer.write(Buffer::from(noise(2048))).await?;
+        let file = writer.close().await?.file;
+        assert!(file.inline().is_none());
+        assert_eq!(file.chunks().len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_allow_empty() -> Result<()> {
+        let fs = Fs::builder(memory()).build().await?;
+        let result = fs.new_file_writer("a").close().await?;
+        assert_eq!(result.file.size(), 0);
+        assert!(result.file.chunks().is_empty());
+
+        let err = fs
+            .new_file_writer("b")
+            .with_allow_empty(false)
+            .close()
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::EmptyFile { path }) if path == "b"
+        ));
+
+        let mut writer = fs.new_file_writer("c").with_allow_empty(false);
+        writer.write(Buffer::from("x")).await?;
+        assert_eq!(writer.close().await?.file.size(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_idle_flush() -> Result<()> {
+        let op = memory();
+        let fs = Fs::builder(op.clone()).chunk_size(1024).build().await?;
+        let mut writer = fs
+            .new_file_writer("log")
+            .with_idle_flush(Duration::from_millis(50));
+        writer.write(Buffer::from(text(100))).await?;
+        assert!(!writer.flush_if_idle().await?);
+
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        assert!(writer.flush_if_idle().await?);
+        assert!(!writer.flush_if_idle().await?);
+        let state = writer.checkpoint();
+        assert_eq!(
+            (state.chunk_sizes.clone(), state.total_size),
+            (vec![100], 100)
+        );
+        drop(writer);
+
+        // The flushed tail survives the writer and is continued by a new one.
+        let mut fs = Fs::create(op).await?;
+        let mut writer = fs.resume_file_writer("log", state);
+        writer.write(Buffer::from(noise(2000))).await?;
+        let file = writer.close().await?.file;
+        assert_eq!(file.chunk_sizes()[0], 100);
+        let mut expected = text(100);
+        expected.extend(noise(2000));
+        fs.insert_file(file);
+        assert_eq!(fs.read("log").await?.to_vec(), expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cdc_prepend_changes_first_chunk() -> Result<()> {
+        let content = noise(64 * 1024);
+        let mut prepended = b"0123456789".to_vec();
+        prepended.extend_from_slice(&content);
+
+        let fs = Fs::builder(memory())
+            .chunking(Chunking::Cdc {
+                min: 256,
+                avg: 1024,
+                max: 4096,
+            })
+            .build()
+            .await?;
+        let a = write_file(&fs, "a", &content).await?.file;
+        let b = write_file(&fs, "b", &prepended).await?.file;
+        assert!(a.chunks().len() > 8);
+        assert!(a.chunk_sizes()[..a.chunks().len() - 1]
+            .iter()
+            .all(|&size| (256..=4096).contains(&size)));
+        assert_ne!(a.chunks()[0], b.chunks()[0]);
+        assert_eq!(a.chunks()[1..], b.chunks()[1..]);
+        let first = a.chunk_sizes()[0] as usize;
+        assert_eq!(
+            a.chunks()[0],
+            Blake3Hasher.chunk_id(&Buffer::from(content[..first].to_vec()))
+        );
+
+        // Every fixed size chunk is shifted instead.
+        let fs = Fs::builder(memory()).chunk_size(1024).build().await?;
+        let a = write_file(&fs, "a", &content).await?.file;
+        let b = write_file(&fs, "b", &prepended).await?.file;
+        assert!(a.chunks().iter().all(|id| !b.chunks().contains(id)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_uploads() -> Result<()> {
+        let content = noise(10 * 1024 + 100);
+        let sequential = Fs::builder(memory())
+            .chunk_size(1024)
+            .upload_concurrency(1)
+            .build()
+            .await?;
+        let expected = write_file(&sequential, "a", &content).await?.file;
+
+        let store = MemoryChunkStore::default();
+        let mut fs = Fs::builder(memory())
+            .chunk_store(store.clone())
+            .chunk_size(1024)
+            .upload_concurrency(8)
+            .build()
+            .await?;
+        let mut writer = fs.new_file_writer("a");
+        for part in content.chunks(700) {
+            writer.write(Buffer::from(part.to_vec())).await?;
+        }
+        let file = writer.close().await?.file;
+        assert_eq!(file.chunks(), expected.chunks());
+        assert_eq!(file.size(), content.len() as u64);
+        assert_eq!(store.puts(), 11);
+        fs.insert_file(file);
+        assert_eq!(fs.read("a").await?.to_vec(), content);
+
+        store.fail_puts(true);
+        let mut writer = fs.new_file_writer("b");
+        let err = writer
+            .write(Buffer::from(text(9 * 1024)))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("injected failure"), "{err}");
+        assert_eq!(store.puts(), 11);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_async_write() -> Result<()> {
+        use tokio::io::AsyncWriteExt as _;
+
+        let fs = Fs::builder(memory()).chunk_size(1024).build().await?;
+        let content = noise(10 * 1024 + 100);
+        let expected = write_file(&fs, "a", &content).await?.file;
+
+        let mut writer = tokio::io::BufWriter::new(fs.new_file_writer("b"));
+        let copied = tokio::io::copy(&mut content.as_slice(), &mut writer).await?;
+        assert_eq!(copied, content.len() as u64);
+        writer.shutdown().await?;
+        let file = writer.into_inner().close().await?.file;
+        assert_eq!(file.chunks(), expected.chunks());
+        assert_eq!(file.size(), expected.size());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_poison_after_failed_upload() -> Result<()> {
+        let store = MemoryChunkStore::default();
+        let fs = Fs::builder(memory())
+            .chunk_store(store.clone())
+            .chunk_size(1024)
+            .upload_concurrency(1)
+            .build()
+            .await?;
+        let poisoned = |err: anyhow::Error| {
+            matches!(
+                err.downcast_ref::<Error>(),
+                Some(Error::Poisoned { path }) if path == "a"
+            )
+        };
+
+        let mut writer = fs.new_file_writer("a");
+        writer.write(Buffer::from(text(2048))).await?;
+        store.fail_puts(true);
+        assert!(writer.write(Buffer::from(noise(2048))).await.is_err());
+        store.fail_puts(false);
+        let err = writer.write(Buffer::from(text(10))).await.unwrap_err();
+        assert!(poisoned(err));
+        assert!(poisoned(writer.close().await.unwrap_err()));
+        assert_eq!(writer.checkpoint().chunks.len(), 2);
+
+        let mut writer = fs.new_file_writer("a");
+        store.fail_puts(true);
+        let err = tokio::io::copy(&mut &noise(9000)[4000..], &mut writer)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("injected failure"), "{err}");
+        store.fail_puts(false);
+        assert!(poisoned(writer.close().await.unwrap_err()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_append_writer() -> Result<()> {
+        let store = MemoryChunkStore::default();
+        let mut fs = Fs::builder(memory())
+            .chunk_store(store.clone())
+            .chunk_size(1024)
+            .build()
+            .await?;
+        let content = noise(5500);
+        let expected = write_file(&fs, "expected", &content).await?.file;
+
+        // The short tail chunk is re-packed with appended data.
+        fs.insert_file(write_file(&fs, "a", &content[..2500]).await?.file);
+        let gets = store.gets();
+        let mut writer = fs.append_writer("a").await?;
+        assert_eq!(store.gets() - gets, 1);
+        writer.write(Buffer::from(content[2500..].to_vec())).await?;
+        let file = writer.close().await?.file;
+        assert_eq!(file.chunks(), expected.chunks());
+        assert_eq!(file.size(), 5500);
+        fs.insert_file(file);
+        assert_eq!(fs.read("a").await?.to_vec(), content);
+
+        // Files ending at a chunk boundary are appended without reading.
+        fs.insert_file(write_file(&fs, "b", &content[..2048]).await?.file);
+        let gets = store.gets();
+        let mut writer = fs.append_writer("b").await?;
+        writer.write(Buffer::from(content[2048..].to_vec())).await?;
+        let file = writer.close().await?.file;
+        assert_eq!(store.gets(), gets);
+        assert_eq!(file.chunks(), expected.chunks());
+
+        // Inline content is moved into chunks once it grows past a chunk.
+        let mut writer = fs
+            .new_file_writer("c")
+            .with_inline_policy(InlinePolicy::Always);
+        writer.write(Buffer::from(content[..100].to_vec())).await?;
+        fs.insert_file(writer.close().await?.file);
+        let mut writer = fs.append_writer("c").await?;
+        writer.write(Buffer::from(content[100..].to_vec())).await?;
+        let file = writer.close().await?.file;
+        assert!(file.inline().is_none());
+        assert_eq!(file.chunks(), expected.chunks());
+
+        assert!(fs.append_writer("missing").await.is_err());
         Ok(())
     }
 }
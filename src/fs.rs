@@ -1,68 +1,840 @@
-use crate::file::DEFAULT_CHUNK_SIZE;
-use crate::specs::v1 as specs_v1;
-use crate::{file::FileWriter, File};
+use crate::audit::StorageAudit;
+use crate::chunking::{Chunker, Chunking};
+use crate::cipher::{Cipher, CIPHER_NAME};
+use crate::codec::{Codec, ENCRYPTED_FLAG};
+use crate::file::{fixed_chunk_sizes, FileWriter, InlinePolicy, WriterState};
+use crate::gc::GcReport;
+use crate::hasher::{Blake3Hasher, HashState, Hasher};
+use crate::info::FsInfo;
+use crate::load::{LoadOptions, LoadReport, OnError};
+use crate::patch::ManifestPatch;
+use crate::reader::FileReader;
+use crate::specs::{v1 as specs_v1, v2 as specs_v2};
+use crate::stats::Hotspots;
+use crate::store::{ChunkStore, OpendalChunkStore};
+use crate::streaming::StreamingFileWriter;
+use crate::verify::{VerifyOptions, VerifyReport};
+use crate::{Error, File};
 use anyhow::anyhow;
 use anyhow::Result;
-use base64::Engine as _;
-use chrono::Utc;
-use futures::StreamExt;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
 use opendal::{Buffer, ErrorKind, Operator};
-use std::{collections::BTreeMap, sync::Arc};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The max times to retry a corrupted chunk upload.
+const UPLOAD_RETRIES: usize = 3;
+
+/// Chunks larger than this are hashed and compressed on the blocking thread
+/// pool.
+const BLOCKING_HASH_SIZE: usize = 1024 * 1024;
+
+/// The max number of files in one manifest segment, tiny in tests so that
+/// segmented manifests are exercised without writing many files.
+const MANIFEST_SEGMENT_FILES: usize = if cfg!(test) { 4 } else { 10_000 };
+
+/// The number of chunks deleted in one batch by gc.
+const GC_BATCH_SIZE: usize = 1000;
+
+/// The max times to commit metadata on etag conflicts.
+const COMMIT_ATTEMPTS: usize = 5;
+
+/// The magic prefix of encrypted metadata.
+const ENCRYPTED_METADATA_MAGIC: &[u8] = b"EPOCHFS-ENCRYPTED-V1\0";
+
+/// The associated data of encrypted metadata.
+const METADATA_AAD: &[u8] = b"epochfs-metadata";
 
 pub struct FsContext {
     op: Operator,
+    store: Arc<dyn ChunkStore>,
     metadata_path: String,
-    data_path: String,
-    version: usize,
+    sealed_path: String,
+    pins_path: String,
+    version: Mutex<usize>,
+    /// The manifest that files in memory are loaded from, `None` if they
+    /// are not loaded from any.
+    manifest: Mutex<Option<String>>,
+    verify_existing: bool,
+    verify_existing_content: bool,
+    verify_uploads: bool,
+    verify_given_ids: bool,
+    repair_sizes: bool,
+    inline_policy: InlinePolicy,
+    codec: Codec,
+    config: specs_v2::Config,
+    chunker: Chunker,
+    upload_concurrency: usize,
+    read_concurrency: usize,
+    hasher: Arc<dyn Hasher>,
+    cipher: Option<Cipher>,
 
-    previous_etag: String,
+    previous_etag: Mutex<String>,
 }
 
 impl FsContext {
+    pub(crate) fn inline_policy(&self) -> InlinePolicy {
+        self.inline_policy
+    }
+
+    /// The size used to split files into chunks.
+    pub(crate) fn chunk_size(&self) -> usize {
+        self.config.chunk_size as usize
+    }
+
+    /// The max number of chunks uploaded concurrently by a writer.
+    pub(crate) fn upload_concurrency(&self) -> usize {
+        self.upload_concurrency
+    }
+
+    /// The max number of chunks fetched concurrently by a read.
+    pub(crate) fn read_concurrency(&self) -> usize {
+        self.read_concurrency
+    }
+
+    /// The hasher of chunk ids.
+    pub(crate) fn hasher(&self) -> &dyn Hasher {
+        self.hasher.as_ref()
+    }
+
+    /// Calculate the chunk id from the buffer without blocking the async
+    /// executor.
+    ///
+    /// Chunk id is used to identify the chunk in the storage. If the chunk
+    /// id already exists, we can reuse the existing chunk instead of creating
+    /// a new one.
+    async fn chunk_id_nonblocking(&self, buf: Buffer) -> Result<String> {
+        let state = update_nonblocking(self.hasher.start(), buf).await?;
+        Ok(state.finish())
+    }
+
+    /// The chunker used to split files into chunks.
+    pub(crate) fn chunker(&self) -> Chunker {
+        self.chunker
+    }
+
+    /// The path of the sentinel object of a sealed version.
+    fn sealed_path(&self, version: usize) -> String {
+        format!("{}/{}", self.sealed_path, version)
+    }
+
+    /// The path of the marker object keeping a pin alive.
+    fn pin_path(&self, pin: &str) -> String {
+        format!("{}/{}", self.pins_path, pin)
+    }
+
+    /// Write the file entry as a pin chunk, returning the pin id.
+    ///
+    /// A marker object is written under the pins path as well, so that gc
+    /// keeps the pin and its chunks until [`Fs::unpin`].
+    pub(crate) async fn write_pin(&self, file: &File) -> Result<String> {
+        let entry: specs_v2::File = file.clone().into();
+        let content = bincode::encode_to_vec(entry, bincode::config::standard())?;
+        let pin = self.write_chunk(content.into()).await?;
+        self.op
+            .write(&self.pin_path(&pin), Vec::<u8>::new())
+            .await?;
+        Ok(pin)
+    }
+
+    /// The version of metadata that the next commit is based on.
+    fn version(&self) -> usize {
+        *self.version.lock().unwrap()
+    }
+
+    /// The manifest that our changes are based on, see [`Fs::write_metadata`].
+    fn base_manifest(&self) -> Option<String> {
+        self.manifest.lock().unwrap().clone()
+    }
+
+    fn set_base_manifest(&self, manifest: &str) {
+        *self.manifest.lock().unwrap() = Some(manifest.to_string());
+    }
+
+    /// The etag of metadata that the next commit is conditioned on.
+    fn previous_etag(&self) -> String {
+        self.previous_etag.lock().unwrap().clone()
+    }
+
+    /// Remember the etag of metadata written by us.
+    async fn update_etag(&self, written: opendal::Metadata) -> Result<()> {
+        let etag = match written.etag() {
+            Some(etag) => etag.to_string(),
+            None if !self.op.info().full_capability().write_with_if_match => "*".to_string(),
+            None => match self.op.stat(&self.metadata_path).await?.etag() {
+                Some(etag) => etag.to_string(),
+                None => return Err(anyhow!("metadata written without etag")),
+            },
+        };
+        *self.previous_etag.lock().unwrap() = etag;
+        Ok(())
+    }
+
     /// Write a chunk to the file system.
     ///
     /// The chunk id is a hash of input data, and is used to identify
     /// the chunk in the storage.
     pub async fn write_chunk(&self, buf: Buffer) -> Result<String> {
-        let chunk_id = chunk_id(buf.clone());
-        let chunk_path = format!("{}/{}", self.data_path, &chunk_id);
-        self.op.write(&chunk_path, buf).await?;
+        let (chunk_id, _, _) = self.upload_chunk(buf).await?;
         Ok(chunk_id)
     }
+
+    /// Upload a chunk if it doesn't exist yet.
+    ///
+    /// Returning the chunk id, whether the chunk is newly uploaded and the
+    /// stored size of the chunk.
+    pub(crate) async fn upload_chunk(&self, buf: Buffer) -> Result<(String, bool, u64)> {
+        let chunk_id = self.chunk_id_nonblocking(buf.clone()).await?;
+        let (is_new, stored_size) = self.put_chunk(&chunk_id, buf).await?;
+        Ok((chunk_id, is_new, stored_size))
+    }
+
+    /// Write a chunk with a precomputed chunk id to skip hashing.
+    ///
+    /// The caller must make sure the id matches the content, it's verified
+    /// against the recomputed one if `verify_given_ids` is enabled. Ids that
+    /// are not well-formed for the hasher are always rejected.
+    pub async fn write_chunk_with_id(&self, buf: Buffer, chunk_id: &str) -> Result<String> {
+        if !self.hasher.is_valid_id(chunk_id) {
+            return Err(anyhow!("chunk id {chunk_id} is not well-formed"));
+        }
+        if self.verify_given_ids {
+            let expected = self.chunk_id_nonblocking(buf.clone()).await?;
+            if expected != chunk_id {
+                return Err(anyhow!(
+                    "chunk id mismatch: given {chunk_id}, expected {expected}"
+                ));
+            }
+        }
+        self.put_chunk(chunk_id, buf).await?;
+        Ok(chunk_id.to_string())
+    }
+
+    /// Put the chunk to the storage if it doesn't exist yet.
+    ///
+    /// Returning whether the chunk is newly uploaded and the stored size of
+    /// the chunk.
+    ///
+    /// The existence check is skipped if `verify_existing` is disabled.
+    pub(crate) async fn put_chunk(&self, chunk_id: &str, buf: Buffer) -> Result<(bool, u64)> {
+        let (codec, body) = self.compress_chunk(buf).await?;
+        if self.verify_existing {
+            let expected = self.stored_size(body.len());
+            if let Some(size) = self.reusable_chunk(chunk_id, expected).await? {
+                return Ok((false, size));
+            }
+        }
+        let size = self.store_chunk(chunk_id, codec, body).await?;
+        Ok((true, size))
+    }
+
+    /// Check whether the existing chunk could be reused instead of storing
+    /// the chunk with the expected stored size.
+    ///
+    /// Chunks written by another codec differ in size, they are reused if
+    /// their content still hashes to the id. Other existing chunks with a
+    /// different size are treated as corrupted and overwritten.
+    ///
+    /// Returning the stored size of the reusable chunk.
+    async fn reusable_chunk(&self, chunk_id: &str, expected: u64) -> Result<Option<u64>> {
+        match self.store.size(chunk_id).await? {
+            None => Ok(None),
+            Some(size) if size == expected => {
+                if self.verify_existing_content {
+                    self.verify_existing_chunk(chunk_id).await?;
+                }
+                Ok(Some(size))
+            }
+            Some(size) => {
+                if self.config.chunk_headers && self.stored_chunk_matches(chunk_id).await? {
+                    return Ok(Some(size));
+                }
+                log::warn!(
+                    "chunk {chunk_id} exists with size {size} instead of {expected}, overwriting"
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Put the compressed chunk body to the storage, verifying the stored
+    /// content if `verify_uploads` is enabled.
+    ///
+    /// Corrupted uploads will be retried up to `UPLOAD_RETRIES` times.
+    /// Returning the stored size of the chunk.
+    async fn store_chunk(&self, chunk_id: &str, codec: Codec, body: Buffer) -> Result<u64> {
+        for attempt in 0..=UPLOAD_RETRIES {
+            let content = self.seal_chunk(chunk_id, codec, body.clone())?;
+            let size = content.len() as u64;
+            self.store.put(chunk_id, content).await?;
+            if !self.verify_uploads || self.stored_chunk_matches(chunk_id).await? {
+                return Ok(size);
+            }
+            log::warn!("chunk {chunk_id} is corrupted after upload, attempt {attempt}");
+        }
+        Err(anyhow!(
+            "chunk {chunk_id} is still corrupted after {} uploads",
+            UPLOAD_RETRIES + 1
+        ))
+    }
+
+    /// Check whether the stored chunk hashes to its id.
+    async fn stored_chunk_matches(&self, chunk_id: &str) -> Result<bool> {
+        match self.store.get(chunk_id).await? {
+            Some(buf) => self.stored_content_matches(chunk_id, buf).await,
+            None => Ok(false),
+        }
+    }
+
+    /// Check whether the stored object is the content of the chunk.
+    ///
+    /// Objects that fail to decode never match.
+    async fn stored_content_matches(&self, chunk_id: &str, buf: Buffer) -> Result<bool> {
+        match self.open(chunk_id, buf) {
+            Ok(buf) => Ok(self.chunk_id_nonblocking(buf).await? == chunk_id),
+            Err(_) => Ok(false),
+        }
+    }
+
+    /// Compress the chunk by the codec, it's stored as is instead if a
+    /// probe shows it's incompressible or it doesn't shrink.
+    ///
+    /// Returning the codec actually used and the compressed body. Large
+    /// chunks are compressed by `spawn_blocking` like hashing.
+    async fn compress_chunk(&self, buf: Buffer) -> Result<(Codec, Buffer)> {
+        let codec = self.codec;
+        if codec == Codec::None {
+            return Ok((codec, buf));
+        }
+        let blocking = buf.len() >= BLOCKING_HASH_SIZE;
+        let compress = move || {
+            if !codec.compressible(&buf) {
+                return Ok((Codec::None, buf));
+            }
+            let body = codec.compress(&buf)?;
+            if body.len() < buf.len() {
+                Ok((codec, body))
+            } else {
+                Ok((Codec::None, buf))
+            }
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) if blocking => handle.spawn_blocking(compress).await?,
+            _ => compress(),
+        }
+    }
+
+    /// The size of the stored object of a compressed chunk body.
+    fn stored_size(&self, body_len: usize) -> u64 {
+        let mut size = body_len;
+        if self.cipher.is_some() {
+            size += Cipher::OVERHEAD;
+        }
+        if self.config.chunk_headers {
+            size += 1;
+        }
+        size as u64
+    }
+
+    /// Encode the compressed chunk body into the stored object.
+    ///
+    /// The body is encrypted if encryption is enabled, and prefixed by the
+    /// header of its codec and cipher unless the file system was upgraded
+    /// from spec v1. Encrypted bodies are bound to the chunk id and header.
+    fn seal_chunk(&self, chunk_id: &str, codec: Codec, body: Buffer) -> Result<Buffer> {
+        let mut flags = codec.tag();
+        if self.cipher.is_some() {
+            flags |= ENCRYPTED_FLAG;
+        }
+        let body = match &self.cipher {
+            Some(cipher) => cipher.encrypt(body, &chunk_aad(chunk_id, flags))?,
+            None => body,
+        };
+        if !self.config.chunk_headers {
+            return Ok(body);
+        }
+        Ok(Buffer::from(vec![
+            Bytes::from(vec![flags]),
+            body.to_bytes(),
+        ]))
+    }
+
+    /// Decode the stored object into the chunk content.
+    ///
+    /// Every chunk is decrypted and decompressed by its own header, so
+    /// chunks written by different codecs could be mixed in one file.
+    fn open(&self, chunk_id: &str, buf: Buffer) -> Result<Buffer> {
+        if !self.config.chunk_headers {
+            return match &self.cipher {
+                Some(cipher) => cipher.decrypt(buf, &chunk_aad(chunk_id, ENCRYPTED_FLAG)),
+                None => Ok(buf),
+            };
+        }
+        let bs = buf.to_bytes();
+        let Some(&flags) = bs.first() else {
+            return Err(anyhow!("chunk is missing its header"));
+        };
+        let body = Buffer::from(bs.slice(1..));
+        let body = match (flags & ENCRYPTED_FLAG != 0, &self.cipher) {
+            (true, Some(cipher)) => cipher.decrypt(body, &chunk_aad(chunk_id, flags))?,
+            (false, None) => body,
+            (true, None) => return Err(anyhow!("chunk is encrypted but no key is configured")),
+            (false, Some(_)) => return Err(anyhow!("chunk is not encrypted")),
+        };
+        Codec::decompress(flags & !ENCRYPTED_FLAG, body)
+    }
+
+    /// Verify that the existing chunk's content actually hashes to its id.
+    ///
+    /// Some other tools could write objects under the same path, we should
+    /// not trust them for dedup.
+    async fn verify_existing_chunk(&self, chunk_id: &str) -> Result<()> {
+        let existing = self.read_chunk(chunk_id).await?;
+        let actual = self.chunk_id_nonblocking(existing).await?;
+        if actual != chunk_id {
+            return Err(anyhow!(
+                "chunk {chunk_id} exists with foreign content hashed to {actual}"
+            ));
+        }
+        Ok(())
+    }
+
+    /// List the ids of all chunks stored in the file system.
+    ///
+    /// Chunks are yielded lazily while paging through the storage, so the
+    /// whole id set is never held in memory.
+    pub fn list_chunks(&self) -> impl Stream<Item = Result<String>> + '_ {
+        self.store.list()
+    }
+
+    /// Read a chunk from the file system by its id.
+    ///
+    /// The content is decrypted and decompressed by the chunk header.
+    pub async fn read_chunk(&self, chunk_id: &str) -> Result<Buffer> {
+        let buf = self.fetch_chunk(chunk_id).await?;
+        self.open(chunk_id, buf)
+    }
+
+    /// Read the chunk at `idx` of the file.
+    ///
+    /// The stored object is checked against the recorded stored size if
+    /// it's known, before decoding it.
+    pub(crate) async fn read_file_chunk(&self, file: &File, idx: usize) -> Result<Buffer> {
+        let chunk_id = &file.chunks()[idx];
+        let buf = self.fetch_chunk(chunk_id).await?;
+        if let Some(&expected) = file.stored_sizes().get(idx) {
+            if buf.len() as u64 != expected {
+                return Err(anyhow!(
+                    "chunk {chunk_id} of file {} is stored with {} bytes, expect {expected}",
+                    file.path(),
+                    buf.len()
+                ));
+            }
+        }
+        self.open(chunk_id, buf)
+    }
+
+    /// Fetch the stored object of a chunk.
+    async fn fetch_chunk(&self, chunk_id: &str) -> Result<Buffer> {
+        self.store
+            .get(chunk_id)
+            .await?
+            .ok_or_else(|| anyhow!("chunk {chunk_id} is missing from storage"))
+    }
 }
 
-pub struct Fs {
-    ctx: Arc<FsContext>,
-    files: BTreeMap<String, File>,
+/// Builder of [`Fs`].
+pub struct FsBuilder {
+    op: Operator,
+    store: Option<Arc<dyn ChunkStore>>,
+    chunk_size: Option<usize>,
+    chunking: Option<Chunking>,
+    upload_concurrency: usize,
+    read_concurrency: usize,
+    hasher: Arc<dyn Hasher>,
+    retain_versions: Option<usize>,
+    verify_existing: bool,
+    verify_existing_content: bool,
+    verify_uploads: bool,
+    verify_given_ids: bool,
+    repair_sizes: bool,
+    inline_policy: InlinePolicy,
+    compression: Option<Codec>,
+    encryption_key: Option<[u8; 32]>,
 }
 
-impl Fs {
-    pub async fn create(op: Operator) -> Result<Self> {
+impl FsBuilder {
+    /// Use a custom chunk store instead of storing chunks under `data` of
+    /// the operator.
+    pub fn chunk_store(mut self, store: impl ChunkStore) -> Self {
+        self.store = Some(Arc::new(store));
+        self
+    }
+
+    /// Set the size used to split files into chunks.
+    ///
+    /// The chunk size is recorded in metadata, and used by later opens if
+    /// not set explicitly. Default to [`crate::DEFAULT_CHUNK_SIZE`].
+    pub fn chunk_size(mut self, size: usize) -> Self {
+        self.chunk_size = Some(size);
+        self
+    }
+
+    /// Set the strategy of splitting files into chunks.
+    ///
+    /// Like chunk size, it's recorded in metadata and used by later opens if
+    /// not set explicitly. Default to [`Chunking::Fixed`].
+    pub fn chunking(mut self, chunking: Chunking) -> Self {
+        self.chunking = Some(chunking);
+        self
+    }
+
+    /// Set the max number of chunks uploaded concurrently by a writer.
+    ///
+    /// Default to `4`.
+    pub fn upload_concurrency(mut self, n: usize) -> Self {
+        self.upload_concurrency = n.max(1);
+        self
+    }
+
+    /// Set the max number of chunks fetched concurrently while exporting or
+    /// reading a file, and of parts read while loading one.
+    ///
+    /// Default to `4`.
+    pub fn read_concurrency(mut self, n: usize) -> Self {
+        self.read_concurrency = n.max(1);
+        self
+    }
+
+    /// Set the hasher of chunk ids.
+    ///
+    /// The hasher is recorded in metadata, opening a file system created
+    /// by another hasher is rejected. Default to [`Blake3Hasher`].
+    pub fn hasher(mut self, hasher: impl Hasher) -> Self {
+        self.hasher = Arc::new(hasher);
+        self
+    }
+
+    /// Set the number of previous versions to retain in metadata.
+    ///
+    /// Retained versions could be loaded by [`Fs::load_version`] and
+    /// [`Fs::load_as_of`], and their chunks are kept by [`Fs::gc`]. Like
+    /// chunk size, it's recorded in metadata. Default to `0`.
+    pub fn retain_versions(mut self, n: usize) -> Self {
+        self.retain_versions = Some(n);
+        self
+    }
+
+    /// Check whether a chunk exists with the expected size before uploading
+    /// it, existing chunks are reused without uploading again.
+    ///
+    /// Disable this to trust the hash and always upload chunks without the
+    /// extra `stat`. Default to `true`.
+    pub fn verify_existing(mut self, v: bool) -> Self {
+        self.verify_existing = v;
+        self
+    }
+
+    /// Verify the content of existing chunks before reusing them.
+    ///
+    /// Enable this if the storage is shared with other tools that could
+    /// write foreign objects under the chunk paths. Default to `false`.
+    pub fn verify_existing_content(mut self, v: bool) -> Self {
+        self.verify_existing_content = v;
+        self
+    }
+
+    /// Read back and verify every uploaded chunk against its id, corrupted
+    /// uploads will be retried. Default to `false`.
+    pub fn verify_uploads(mut self, v: bool) -> Self {
+        self.verify_uploads = v;
+        self
+    }
+
+    /// Verify ids given to [`FsContext::write_chunk_with_id`] against the
+    /// rehashed content. Default to `true` in debug builds only.
+    pub fn verify_given_ids(mut self, v: bool) -> Self {
+        self.verify_given_ids = v;
+        self
+    }
+
+    /// Repair file sizes that disagree with their chunk sizes while reading
+    /// manifests, instead of returning an error. Default to `false`.
+    pub fn repair_sizes(mut self, v: bool) -> Self {
+        self.repair_sizes = v;
+        self
+    }
+
+    /// Set the policy of storing small files inline in the manifest.
+    ///
+    /// Default to [`InlinePolicy::Never`].
+    pub fn inline_policy(mut self, policy: InlinePolicy) -> Self {
+        self.inline_policy = policy;
+        self
+    }
+
+    /// Compress chunks with the codec before storing them.
+    ///
+    /// Chunks that don't shrink are stored as is. Every chunk records its
+    /// codec in the header, so changing the codec never breaks reading
+    /// existing chunks. Like chunk size, it's recorded in metadata. Default
+    /// to [`Codec::None`].
+    pub fn compression(mut self, codec: Codec) -> Self {
+        self.compression = Some(codec);
+        self
+    }
+
+    /// Encrypt chunks, manifests and metadata with the given key.
+    ///
+    /// Chunk ids are still hashed over the plaintext, so the same content
+    /// written with the same key gets the same ids everywhere and dedup
+    /// keeps working.
+    ///
+    /// The key is required to open an encrypted file system, and rejected
+    /// by plaintext ones. Default to no encryption.
+    pub fn encryption_key(mut self, key: [u8; 32]) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    pub async fn build(self) -> Result<Fs> {
+        if self.chunk_size == Some(0) {
+            return Err(anyhow!("chunk size must be larger than 0"));
+        }
+        if let Some(Chunking::Cdc { min, avg, max }) = self.chunking {
+            if min == 0 || min > avg || avg > max {
+                return Err(anyhow!(
+                    "invalid cdc sizes {min}/{avg}/{max}, expect 0 < min <= avg <= max"
+                ));
+            }
+        }
+
+        let op = &self.op;
+        let cipher = self.encryption_key.as_ref().map(Cipher::new);
+        let mut config = None;
+        let mut version = 0;
         let previous_etag = match op.stat("metadata").await {
-            Ok(stat) => stat
-                .etag()
-                .ok_or_else(|| {
-                    anyhow!("input storage services doesn't have etag: {:?}", op.info())
-                })?
-                .to_string(),
+            Ok(stat) => {
+                let content = op.read("metadata").await?;
+                let metadata = decode_metadata_content(content, cipher.as_ref())?;
+                if metadata.config.hasher != self.hasher.name() {
+                    return Err(anyhow!(
+                        "file system uses hasher {}, but {} is configured",
+                        metadata.config.hasher,
+                        self.hasher.name()
+                    ));
+                }
+                config = Some(metadata.config);
+                version = metadata.version;
+                metadata_etag(op, &stat)?
+            }
             Err(err) if err.kind() == ErrorKind::NotFound => "*".to_string(),
             Err(err) => return Err(err.into()),
         };
 
+        self.finish(previous_etag, version, config, cipher)
+    }
+
+    /// Initialize a brand-new file system with the configured options.
+    ///
+    /// The config, like chunk size, chunking, compression and encryption,
+    /// is recorded by the same create-only conditional write as
+    /// [`Fs::init`], so [`Error::AlreadyExists`] is returned if a file system
+    /// already exists.
+    pub async fn init(self) -> Result<Fs> {
+        match self.op.stat("metadata").await {
+            Ok(_) => return Err(Error::AlreadyExists.into()),
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        let fs = self.build().await?;
+
+        let manifest = fs.write_manifest().await?;
+        let metadata_content = fs.encode_metadata(&manifest, 0).await?;
+        let cap = fs.ctx.op.info().full_capability();
+        let write = fs
+            .ctx
+            .op
+            .write_with(&fs.ctx.metadata_path, metadata_content);
+        let write = if cap.write_with_if_not_exists {
+            write.if_not_exists(true)
+        } else if cap.write_with_if_none_match {
+            write.if_none_match("*")
+        } else {
+            write
+        };
+        match write.await {
+            Ok(written) => {
+                fs.ctx.update_etag(written).await?;
+                fs.ctx.set_base_manifest(&manifest);
+                Ok(fs)
+            }
+            Err(err) if err.kind() == ErrorKind::ConditionNotMatch => {
+                Err(Error::AlreadyExists.into())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Build the file system with the known etag and version of metadata.
+    ///
+    /// The recorded config of an existing file system is adopted, and
+    /// options set explicitly on the builder override it. The new config is
+    /// recorded by the next commit.
+    fn finish(
+        self,
+        previous_etag: String,
+        version: usize,
+        config: Option<specs_v2::Config>,
+        cipher: Option<Cipher>,
+    ) -> Result<Fs> {
+        let op = self.op;
+        let store = self
+            .store
+            .unwrap_or_else(|| Arc::new(OpendalChunkStore::new(op.clone(), "data")));
+        let mut config = config.unwrap_or_else(|| specs_v2::Config {
+            hasher: self.hasher.name().to_string(),
+            id_encoding: self.hasher.id_encoding().to_string(),
+            encryption: cipher.as_ref().map(|_| CIPHER_NAME.to_string()),
+            ..Default::default()
+        });
+        if let Some(chunk_size) = self.chunk_size {
+            config.chunk_size = chunk_size as u64;
+        }
+        if let Some(n) = self.retain_versions {
+            config.retain_versions = n as u64;
+        }
+        if let Some(codec) = self.compression {
+            config.compression = codec.name().map(str::to_string);
+            config.compression_level = codec.level();
+        }
+        let codec = Codec::from_config(config.compression.as_deref(), config.compression_level)?;
+        if codec != Codec::None && !config.chunk_headers {
+            return Err(anyhow!(
+                "compression is not supported by file systems upgraded from spec v1"
+            ));
+        }
+        match self.chunking {
+            Some(Chunking::Fixed) => config.cdc = None,
+            Some(Chunking::Cdc { min, avg, max }) => {
+                config.cdc = Some(specs_v2::Cdc {
+                    min: min as u64,
+                    avg: avg as u64,
+                    max: max as u64,
+                })
+            }
+            None => {}
+        }
+        let chunking = match config.cdc {
+            Some(cdc) => Chunking::Cdc {
+                min: cdc.min as usize,
+                avg: cdc.avg as usize,
+                max: cdc.max as usize,
+            },
+            None => Chunking::Fixed,
+        };
+        let chunker = Chunker::new(chunking, config.chunk_size as usize);
         let ctx = Arc::new(FsContext {
             op,
+            store,
             metadata_path: "metadata".to_string(),
-            data_path: "data".to_string(),
-            version: 0,
-            previous_etag,
+            sealed_path: "sealed".to_string(),
+            pins_path: "pins".to_string(),
+            version: Mutex::new(version),
+            manifest: Mutex::new(None),
+            verify_existing: self.verify_existing,
+            verify_existing_content: self.verify_existing_content,
+            verify_uploads: self.verify_uploads,
+            verify_given_ids: self.verify_given_ids,
+            repair_sizes: self.repair_sizes,
+            inline_policy: self.inline_policy,
+            codec,
+            config,
+            chunker,
+            upload_concurrency: self.upload_concurrency,
+            read_concurrency: self.read_concurrency,
+            hasher: self.hasher,
+            cipher,
+            previous_etag: Mutex::new(previous_etag),
         });
 
-        let fs = Self {
+        Ok(Fs {
             ctx,
             files: BTreeMap::new(),
-        };
+        })
+    }
+}
 
-        Ok(fs)
+pub struct Fs {
+    ctx: Arc<FsContext>,
+    files: BTreeMap<String, File>,
+}
+
+impl Fs {
+    /// Open the file system with default options.
+    ///
+    /// The version of existing metadata is adopted, so that later commits
+    /// continue from it.
+    pub async fn create(op: Operator) -> Result<Self> {
+        Self::builder(op).build().await
+    }
+
+    /// Initialize a brand-new file system with an empty manifest and
+    /// default options.
+    ///
+    /// See [`FsBuilder::init`] for initializing with other options.
+    ///
+    /// The metadata is written with a create-only conditional write, so
+    /// [`Error::AlreadyExists`] is returned if a file system already exists.
+    /// Services without conditional writes only get the existence check
+    /// before writing, which could race with another `init`.
+    pub async fn init(op: Operator) -> Result<Self> {
+        Self::builder(op).init().await
+    }
+
+    /// Create a file system backed by a fresh in-memory operator.
+    ///
+    /// It's useful for writing tests against code that consumes [`Fs`].
+    #[cfg(feature = "testing")]
+    pub fn in_memory() -> Self {
+        let op = Operator::new(opendal::services::Memory::default())
+            .expect("memory service must be available")
+            .finish();
+        // The operator is brand new, so there is no metadata yet.
+        Self::builder(op)
+            .finish("*".to_string(), 0, None, None)
+            .expect("default config must be valid")
+    }
+
+    /// The operator that the file system is stored in.
+    pub fn operator(&self) -> &Operator {
+        &self.ctx.op
+    }
+
+    /// Create a builder to configure the file system.
+    pub fn builder(op: Operator) -> FsBuilder {
+        FsBuilder {
+            op,
+            store: None,
+            chunk_size: None,
+            chunking: None,
+            upload_concurrency: 4,
+            read_concurrency: 4,
+            hasher: Arc::new(Blake3Hasher),
+            retain_versions: None,
+            verify_existing: true,
+            verify_existing_content: false,
+            verify_uploads: false,
+            verify_given_ids: cfg!(debug_assertions),
+            repair_sizes: false,
+            inline_policy: InlinePolicy::default(),
+            compression: None,
+            encryption_key: None,
+        }
     }
 
     /// Create a new file writer
@@ -70,102 +842,3204 @@ impl Fs {
         FileWriter::new(self.ctx.clone(), path.to_string())
     }
 
+    /// Create a new streaming file writer.
+    ///
+    /// It's a lower overhead path for large sequential writes, see
+    /// [`StreamingFileWriter`] for details.
+    pub fn new_streaming_writer(&self, path: &str) -> StreamingFileWriter {
+        StreamingFileWriter::new(self.ctx.clone(), path.to_string())
+    }
+
+    /// Resume a file writer from the checkpoint.
+    pub fn resume_file_writer(&self, path: &str, state: WriterState) -> FileWriter {
+        FileWriter::resume(self.ctx.clone(), path.to_string(), state)
+    }
+
+    /// Create a writer appending to the existing file at given path.
+    ///
+    /// Only new data and the re-packed tail chunk are uploaded, insert the
+    /// closed file to replace the existing one.
+    pub async fn append_writer(&self, path: &str) -> Result<FileWriter> {
+        let file = self
+            .stat(path)
+            .ok_or_else(|| anyhow!("file {path} not found"))?;
+        FileWriter::append(self.ctx.clone(), file).await
+    }
+
     pub fn insert_file(&mut self, file: File) {
         self.files.insert(file.path().to_string(), file);
     }
 
-    /// Wirte the manifest to the file system.
+    /// Remove the file at given path, returns the removed file if exists.
     ///
-    /// Returning the chunk id of the manifest.
-    pub async fn write_manifest(&self) -> Result<String> {
-        let manifest = specs_v1::Manifest {
-            files: self.files.clone().into_values().map(File::into).collect(),
-        };
-        let manifest_content: Buffer =
-            bincode::encode_to_vec(manifest, bincode::config::standard())?.into();
-        let chunk_id = self.ctx.write_chunk(manifest_content).await?;
-        Ok(chunk_id)
+    /// The removal is recorded by the next manifest, chunks of the file are
+    /// left for [`Fs::gc`].
+    pub fn remove_file(&mut self, path: &str) -> Option<File> {
+        self.files.remove(path)
+    }
+
+    /// Check whether the file at given path exists.
+    pub fn contains(&self, path: &str) -> bool {
+        self.files.contains_key(path)
+    }
+
+    /// The number of files.
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Returns `true` if there are no files.
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Replace the content of the file at given path.
+    ///
+    /// All chunks are uploaded before the file entry is swapped, so the
+    /// previous entry stays intact if any upload fails.
+    pub async fn replace_file(&mut self, path: &str, data: Buffer) -> Result<File> {
+        let mut writer = self.new_file_writer(path);
+        writer.write(data).await?;
+        let file = writer.close().await?.file;
+
+        self.insert_file(file.clone());
+        Ok(file)
     }
 
-    /// Read the manifest from the file system.
-    pub async fn read_manifest(&mut self, manifest_path: &str) -> Result<()> {
-        let mut manifest_content = self.ctx.op.read(manifest_path).await?;
-        let manifest: specs_v1::Manifest =
-            bincode::decode_from_std_read(&mut manifest_content, bincode::config::standard())?;
+    /// Apply a patch computed out of band to the current files.
+    ///
+    /// The patch is applied atomically, files stay untouched if any change
+    /// is invalid.
+    pub async fn apply_patch(&mut self, patch: ManifestPatch) -> Result<()> {
+        if patch.verify_chunks {
+            for add in &patch.adds {
+                for chunk_id in &add.chunks {
+                    if !self.ctx.store.exists(chunk_id).await? {
+                        return Err(anyhow!(
+                            "chunk {chunk_id} of file {} is missing from storage",
+                            add.path
+                        ));
+                    }
+                }
+            }
+        }
 
-        // clear all existsing files before loading.
-        //
-        // TODO: we need to compare the files update time.
-        self.files.clear();
-        for file in manifest.files {
-            self.files.insert(file.path.clone(), file.into());
+        let mut files = self.files.clone();
+        for path in patch.removes {
+            files
+                .remove(&path)
+                .ok_or_else(|| anyhow!("file {path} to remove not found"))?;
+        }
+        for (from, to) in patch.renames {
+            let file = files
+                .remove(&from)
+                .ok_or_else(|| anyhow!("file {from} to rename not found"))?;
+            files.insert(to.clone(), file.with_path(to));
         }
+        let now = Utc::now().timestamp() as u64;
+        for add in patch.adds {
+            let chunk_sizes = match (add.chunk_sizes.is_empty(), self.ctx.config.cdc) {
+                (false, _) => add.chunk_sizes,
+                (true, None) => {
+                    fixed_chunk_sizes(add.size, add.chunks.len(), self.ctx.config.chunk_size)
+                }
+                (true, Some(_)) => {
+                    return Err(anyhow!(
+                        "chunk sizes of file {} are required by cdc chunking",
+                        add.path
+                    ))
+                }
+            };
+            let mut file: File = specs_v2::File {
+                path: add.path,
+                chunks: add.chunks,
+                chunk_sizes,
+                stored_sizes: vec![],
+                inline: None,
+                format: None,
+                size: add.size,
+                last_modified: now,
+            }
+            .into();
+            file.check_size(false)?;
+            files.insert(file.path().to_string(), file);
+        }
+
+        self.files = files;
         Ok(())
     }
 
-    /// TODO: we should support automatically merge.
-    pub async fn write_metadata(&self, manifest_path: &str) -> Result<()> {
-        let metadata = specs_v1::Metadata {
-            version: self.ctx.version,
-            manifest: manifest_path.to_string(),
-            last_modified: Utc::now().timestamp() as u64,
-        };
-        let metadata_content: Buffer =
-            bincode::encode_to_vec(metadata, bincode::config::standard())?.into();
-        self.ctx
-            .op
-            .write_with(&self.ctx.metadata_path, metadata_content)
-            .if_match(&self.ctx.previous_etag)
-            .await?;
+    /// Update the last modified time of the file at given path without
+    /// touching its content.
+    ///
+    /// Use current time if `when` is `None`. The change will be persisted
+    /// on the next manifest write.
+    pub fn touch(&mut self, path: &str, when: Option<DateTime<Utc>>) -> Result<()> {
+        let file = self
+            .files
+            .get_mut(path)
+            .ok_or_else(|| anyhow!("file {path} not found"))?;
+        file.set_last_modified(when.unwrap_or_else(Utc::now));
         Ok(())
     }
 
-    pub async fn read_metadata(&self) -> Result<String> {
-        let mut metadata_content = self.ctx.op.read(&self.ctx.metadata_path).await?;
-        let metadata: specs_v1::Metadata =
-            bincode::decode_from_std_read(&mut metadata_content, bincode::config::standard())?;
+    /// Calculate the merkle root hash of the current snapshot.
+    ///
+    /// Every file is a leaf hashed over its path, chunk ids and size, leaves
+    /// are ordered by path so the root is deterministic for the same files.
+    pub fn merkle_root(&self) -> [u8; 32] {
+        let mut nodes: Vec<blake3::Hash> = self.files.values().map(merkle_leaf).collect();
+        if nodes.is_empty() {
+            return *blake3::hash(b"").as_bytes();
+        }
 
-        if metadata.version != self.ctx.version {
-            return Err(anyhow!("metadata version mismatch"));
+        while nodes.len() > 1 {
+            nodes = nodes
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => {
+                        let mut hasher = blake3::Hasher::new();
+                        hasher.update(&[1]);
+                        hasher.update(left.as_bytes());
+                        hasher.update(right.as_bytes());
+                        hasher.finalize()
+                    }
+                    [node] => *node,
+                    _ => unreachable!(),
+                })
+                .collect();
         }
-        Ok(metadata.manifest)
+        *nodes[0].as_bytes()
     }
 
-    pub async fn load_from(&mut self, external: Operator) -> Result<()> {
-        let mut lister = external.lister("/").await?;
+    /// Report the top `top_n` largest files and most referenced chunks.
+    ///
+    /// Ties are ordered by path or chunk id to make the result deterministic.
+    pub fn hotspots(&self, top_n: usize) -> Hotspots {
+        let mut largest_files: Vec<(String, u64)> = self
+            .files
+            .values()
+            .map(|file| (file.path().to_string(), file.size()))
+            .collect();
+        largest_files.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        largest_files.truncate(top_n);
 
-        while let Some(file) = lister.next().await.transpose()? {
-            let stream = external
-                .reader_with(file.path())
-                .chunk(DEFAULT_CHUNK_SIZE)
-                .concurrent(2)
-                .await?
-                .into_stream(..)
-                .await?;
-            let mut writer = self.new_file_writer(file.path());
-            writer.write_from_stream(stream).await?;
-            let file = writer.close().await?;
-            self.insert_file(file);
+        let mut references: BTreeMap<&str, usize> = BTreeMap::new();
+        for chunk_id in self.files.values().flat_map(|file| file.chunks()) {
+            *references.entry(chunk_id).or_default() += 1;
         }
+        let mut hot_chunks: Vec<(String, usize)> = references
+            .into_iter()
+            .map(|(chunk_id, count)| (chunk_id.to_string(), count))
+            .collect();
+        hot_chunks.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        hot_chunks.truncate(top_n);
 
-        let manifest = self.write_manifest().await?;
-        self.write_metadata(&manifest).await?;
-        Ok(())
+        Hotspots {
+            largest_files,
+            hot_chunks,
+        }
     }
+
+    /// The total stored size of distinct chunks referenced by files.
+    ///
+    /// Shared chunks are counted once, and compressed or encrypted chunks
+    /// count their stored objects instead of the content. Chunks without
+    /// recorded stored sizes are stat from the storage.
+    pub async fn physical_size(&self) -> Result<u64> {
+        let mut sizes: BTreeMap<&str, Option<u64>> = BTreeMap::new();
+        for file in self.files.values() {
+            for (idx, chunk_id) in file.chunks().iter().enumerate() {
+                let size = file.stored_sizes().get(idx).copied();
+                let entry = sizes.entry(chunk_id).or_default();
+                *entry = entry.or(size);
+            }
+        }
+
+        let mut total = 0;
+        for (chunk_id, size) in sizes {
+            total += match size {
+                Some(size) => size,
+                None => self
+                    .ctx
+                    .store
+                    .size(chunk_id)
+                    .await?
+                    .ok_or_else(|| anyhow!("chunk {chunk_id} is missing from storage"))?,
+            };
+        }
+        Ok(total)
+    }
+
+    /// List all files referencing the given chunk id.
+    pub fn files_containing_chunk(&self, chunk_id: &str) -> Vec<&File> {
+        self.files
+            .values()
+            .filter(|file| file.chunks().iter().any(|id| id == chunk_id))
+            .collect()
+    }
+
+    /// List all files in the current snapshot, ordered by path.
+    pub fn list(&self) -> impl Iterator<Item = &File> {
+        self.files.values()
+    }
+
+    /// Stream files of the committed manifest whose path starts with the
+    /// given prefix, ordered by path.
+    ///
+    /// Only segments of the manifest covering the prefix are loaded, one at
+    /// a time, so listing a directory stays cheap for huge file systems.
+    /// Files in memory are not consulted, use [`Fs::list`] for them.
+    pub fn list_dir_stream(&self, prefix: &str) -> impl Stream<Item = Result<File>> + '_ {
+        let prefix = prefix.to_string();
+        stream::once(async move {
+            let segments = self.dir_segments(&prefix).await?;
+            let files = stream::iter(segments)
+                .then(move |segment| {
+                    let prefix = prefix.clone();
+                    async move { self.segment_files(&segment, &prefix).await }
+                })
+                .map_ok(|files| stream::iter(files.into_iter().map(Ok)))
+                .try_flatten();
+            Ok::<_, anyhow::Error>(files)
+        })
+        .try_flatten()
+    }
+
+    /// Get the file at given path, returns `None` if not exists.
+    pub fn stat(&self, path: &str) -> Option<&File> {
+        self.files.get(path)
+    }
+
+    /// Open a reader of the file at given path.
+    pub fn open(&self, path: &str) -> Result<FileReader> {
+        let file = self
+            .stat(path)
+            .ok_or_else(|| anyhow!("file {path} not found"))?;
+        Ok(FileReader::new(self.ctx.clone(), file.clone()))
+    }
+
+    /// Read the whole content of the file at given path.
+    pub async fn read(&self, path: &str) -> Result<Buffer> {
+        let file = self
+            .stat(path)
+            .ok_or_else(|| anyhow!("file {path} not found"))?;
+        self.read_file(file).await
+    }
+
+    /// Read the byte range of the file at given path.
+    ///
+    /// Only chunks overlapping with the range are fetched. Ranges past the
+    /// end of file are rejected.
+    pub async fn read_range(&self, path: &str, range: Range<u64>) -> Result<Buffer> {
+        let mut bufs = self
+            .open(path)?
+            .read_ranges(&[(range.start, range.end)])
+            .await?;
+        Ok(bufs.remove(0))
+    }
+
+    /// Read the whole content of the file by its pin id.
+    ///
+    /// The pin is resolved from storage directly, so it keeps working after
+    /// the file is removed from the manifest as long as its chunks exist.
+    pub async fn read_by_id(&self, pin: &str) -> Result<Buffer> {
+        let file = self.read_pin(pin).await?;
+        self.read_file(&file).await
+    }
+
+    /// Remove the pin, so that gc no longer keeps its chunks.
+    ///
+    /// The pin could still be read until its chunks are collected.
+    pub async fn unpin(&self, pin: &str) -> Result<()> {
+        self.ctx.op.delete(&self.ctx.pin_path(pin)).await?;
+        Ok(())
+    }
+
+    /// Decode the file entry of the pin.
+    async fn read_pin(&self, pin: &str) -> Result<File> {
+        let mut content = self.ctx.read_chunk(pin).await?;
+        let entry: specs_v2::File =
+            bincode::decode_from_std_read(&mut content, bincode::config::standard())?;
+        let mut file: File = entry.into();
+        file.check_size(false)?;
+        Ok(file)
+    }
+
+    /// The ids of pins kept alive by their markers.
+    async fn pins(&self) -> Result<Vec<String>> {
+        let root = format!("{}/", self.ctx.pins_path);
+        let mut pins = vec![];
+        let mut lister = self.ctx.op.lister(&root).await?;
+        while let Some(entry) = lister.try_next().await? {
+            if entry.metadata().is_file() {
+                pins.push(entry.name().to_string());
+            }
+        }
+        Ok(pins)
+    }
+
+    async fn read_file(&self, file: &File) -> Result<Buffer> {
+        FileReader::new(self.ctx.clone(), file.clone())
+            .read_all()
+            .await
+    }
+
+    /// Read the whole content of the file and verify it against the
+    /// expected content hash.
+    ///
+    /// The content hash is calculated in the same way as chunk id by the
+    /// hasher of file system, over the whole file.
+    pub async fn read_verified(&self, path: &str, expected_hash: &str) -> Result<Buffer> {
+        let buf = self.read(path).await?;
+        let actual = self.ctx.chunk_id_nonblocking(buf.clone()).await?;
+        if actual != expected_hash {
+            return Err(anyhow!(
+                "content hash mismatch for file {path}: expected {expected_hash}, actual {actual}"
+            ));
+        }
+        Ok(buf)
+    }
+
+    /// Export all files to the external storage.
+    ///
+    /// Up to `concurrency` files are exported at the same time, and chunks
+    /// of a file are fetched concurrently while written in order.
+    pub async fn export_to(&self, dest: &Operator, concurrency: usize) -> Result<()> {
+        let concurrency = concurrency.max(1);
+        stream::iter(self.files.values())
+            .map(|file| self.export_file_to(file, dest, file.path(), concurrency))
+            .buffer_unordered(concurrency)
+            .try_collect::<()>()
+            .await
+    }
+
+    /// Export a single file to the given path of external storage.
+    ///
+    /// Chunks are fetched concurrently by the configured read concurrency,
+    /// see [`FsBuilder::read_concurrency`].
+    pub async fn export_file(&self, path: &str, dest: &Operator, dest_path: &str) -> Result<()> {
+        let file = self
+            .stat(path)
+            .ok_or_else(|| anyhow!("file {path} not found"))?;
+        self.export_file_to(file, dest, dest_path, self.ctx.read_concurrency())
+            .await
+    }
+
+    /// Export the file's content to the given path of external storage.
+    async fn export_file_to(
+        &self,
+        file: &File,
+        dest: &Operator,
+        dest_path: &str,
+        concurrency: usize,
+    ) -> Result<()> {
+        if let Some(inline) = file.inline() {
+            dest.write(dest_path, inline.clone()).await?;
+            return Ok(());
+        }
+
+        let mut writer = dest.writer(dest_path).await?;
+        let mut chunks = stream::iter(0..file.chunks().len())
+            .map(|idx| self.ctx.read_file_chunk(file, idx))
+            .buffered(concurrency);
+        while let Some(buf) = chunks.try_next().await? {
+            writer.write(buf).await?;
+        }
+        writer.close().await?;
+        Ok(())
+    }
+
+    /// Replicate all files into another file system.
+    ///
+    /// All referenced chunks are copied into the destination's chunk store
+    /// with up to `concurrency` transfers at the same time, and files are
+    /// inserted into the destination. Callers need to commit the destination
+    /// to persist them.
+    pub async fn replicate_to(&self, dest: &mut Fs, concurrency: usize) -> Result<()> {
+        let chunk_ids: BTreeSet<&str> = self
+            .files
+            .values()
+            .flat_map(|file| file.chunks())
+            .map(String::as_str)
+            .collect();
+
+        let stored_sizes: BTreeMap<&str, u64> = stream::iter(chunk_ids)
+            .map(|chunk_id| {
+                let dest = &dest.ctx;
+                async move {
+                    let buf = self.ctx.read_chunk(chunk_id).await?;
+                    let (_, stored_size) = dest.put_chunk(chunk_id, buf).await?;
+                    Ok::<_, anyhow::Error>((chunk_id, stored_size))
+                }
+            })
+            .buffer_unordered(concurrency.max(1))
+            .try_collect()
+            .await?;
+
+        // Chunks could be stored differently by the destination.
+        for file in self.files.values() {
+            let sizes = file.chunks().iter().map(|id| stored_sizes[id.as_str()]);
+            dest.insert_file(file.clone().with_stored_sizes(sizes.collect()));
+        }
+        Ok(())
+    }
+
+    /// Wirte the manifest to the file system.
+    ///
+    /// Returning the chunk id of the manifest.
+    ///
+    /// Files are always encoded in path order, so the same files produce the
+    /// same manifest chunk id no matter how they're inserted.
+    pub async fn write_manifest(&self) -> Result<String> {
+        self.write_files(&self.files).await
+    }
+
+    /// Write the manifest of given files.
+    ///
+    /// Large manifests are split into segments of `MANIFEST_SEGMENT_FILES`
+    /// files, and the returned id points to the index of segments.
+    async fn write_files(&self, files: &BTreeMap<String, File>) -> Result<String> {
+        let mut files: Vec<specs_v2::File> = files.values().cloned().map(File::into).collect();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        if files.len() <= MANIFEST_SEGMENT_FILES {
+            return self.write_segment(files).await;
+        }
+
+        let mut segments = Vec::with_capacity(files.len().div_ceil(MANIFEST_SEGMENT_FILES));
+        let mut files = files.into_iter().peekable();
+        while files.peek().is_some() {
+            let segment: Vec<specs_v2::File> =
+                files.by_ref().take(MANIFEST_SEGMENT_FILES).collect();
+            let first = segment[0].path.clone();
+            let last = segment[segment.len() - 1].path.clone();
+            let id = self.write_segment(segment).await?;
+            segments.push(specs_v2::Segment { id, first, last });
+        }
+        let index = specs_v2::ManifestIndex { segments };
+        let mut content = specs_v2::MANIFEST_INDEX_MAGIC.to_vec();
+        bincode::encode_into_std_write(index, &mut content, bincode::config::standard())?;
+        self.ctx.write_chunk(content.into()).await
+    }
+
+    /// Write files as a single manifest chunk.
+    async fn write_segment(&self, files: Vec<specs_v2::File>) -> Result<String> {
+        let manifest = specs_v2::Manifest { files };
+        let mut manifest_content = specs_v2::MANIFEST_MAGIC.to_vec();
+        bincode::encode_into_std_write(
+            manifest,
+            &mut manifest_content,
+            bincode::config::standard(),
+        )?;
+        let chunk_id = self.ctx.write_chunk(manifest_content.into()).await?;
+        Ok(chunk_id)
+    }
+
+    /// Read the manifest from the file system by its chunk id.
+    ///
+    /// All existing files are replaced, use [`Fs::merge_manifest`] to keep
+    /// local changes.
+    pub async fn read_manifest(&mut self, manifest_id: &str) -> Result<()> {
+        let files = self.read_manifest_into(manifest_id).await?;
+        self.files = files;
+        self.ctx.set_base_manifest(manifest_id);
+        Ok(())
+    }
+
+    /// Merge the manifest into current files.
+    ///
+    /// For paths on both sides, the file with newer `last_modified` is kept,
+    /// and the local one wins on ties. Paths on only one side are kept.
+    pub async fn merge_manifest(&mut self, manifest_id: &str) -> Result<()> {
+        let files = self.read_manifest_into(manifest_id).await?;
+        for (path, file) in files {
+            match self.files.get(&path) {
+                Some(local) if local.last_modified() >= file.last_modified() => {}
+                _ => {
+                    self.files.insert(path, file);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Read and decode the manifest without touching current files.
+    ///
+    /// Both single chunk manifests and segmented manifests are supported.
+    pub async fn read_manifest_into(&self, manifest_id: &str) -> Result<BTreeMap<String, File>> {
+        let content = self.ctx.read_chunk(manifest_id).await?;
+        let Some(index) = decode_manifest_index(content.clone())? else {
+            let mut files = BTreeMap::new();
+            self.decode_segment(content, &mut files)?;
+            return Ok(files);
+        };
+
+        let mut segments = stream::iter(&index)
+            .map(|segment| self.ctx.read_chunk(&segment.id))
+            .buffered(self.ctx.read_concurrency());
+        let mut files = BTreeMap::new();
+        while let Some(content) = segments.try_next().await? {
+            self.decode_segment(content, &mut files)?;
+        }
+        Ok(files)
+    }
+
+    /// The ids of segments of the manifest, empty for single chunk
+    /// manifests.
+    async fn manifest_segments(&self, manifest_id: &str) -> Result<Vec<String>> {
+        let content = self.ctx.read_chunk(manifest_id).await?;
+        Ok(decode_manifest_index(content)?
+            .map(|index| index.into_iter().map(|segment| segment.id).collect())
+            .unwrap_or_default())
+    }
+
+    /// The ids of committed manifest chunks that may contain paths starting
+    /// with `prefix`, in path order.
+    async fn dir_segments(&self, prefix: &str) -> Result<Vec<String>> {
+        let Some(metadata) = self.try_decode_metadata().await? else {
+            return Ok(vec![]);
+        };
+        let content = self.ctx.read_chunk(&metadata.manifest).await?;
+        let Some(index) = decode_manifest_index(content)? else {
+            return Ok(vec![metadata.manifest]);
+        };
+        Ok(index
+            .into_iter()
+            .filter(|segment| segment_covers(segment, prefix))
+            .map(|segment| segment.id)
+            .collect())
+    }
+
+    /// The files of the manifest chunk whose path starts with `prefix`.
+    async fn segment_files(&self, segment: &str, prefix: &str) -> Result<Vec<File>> {
+        let mut files = BTreeMap::new();
+        self.decode_segment(self.ctx.read_chunk(segment).await?, &mut files)?;
+        Ok(files
+            .into_values()
+            .filter(|file| file.path().starts_with(prefix))
+            .collect())
+    }
+
+    /// Decode files of a single manifest chunk into `files`.
+    ///
+    /// Manifests of spec v1 are decoded as well.
+    fn decode_segment(&self, content: Buffer, files: &mut BTreeMap<String, File>) -> Result<()> {
+        let content = content.to_bytes();
+        let decoded: Vec<File> = match content.strip_prefix(specs_v2::MANIFEST_MAGIC) {
+            Some(content) => {
+                let (manifest, _): (specs_v2::Manifest, _) =
+                    bincode::decode_from_slice(content, bincode::config::standard())?;
+                manifest.files.into_iter().map(File::from).collect()
+            }
+            None => {
+                let (manifest, _): (specs_v1::Manifest, _) =
+                    bincode::decode_from_slice(&content, bincode::config::standard())?;
+                manifest.files.into_iter().map(File::from).collect()
+            }
+        };
+        for mut file in decoded {
+            file.check_size(self.ctx.repair_sizes)?;
+            files.insert(file.path().to_string(), file);
+        }
+        Ok(())
+    }
+
+    /// Write the metadata pointing to the given manifest.
+    ///
+    /// Every write bumps the version by one on top of the version we loaded
+    /// or committed last.
+    ///
+    /// If other writers committed in between, our changes since the
+    /// manifest we loaded are merged into the latest manifest, and the
+    /// commit is retried with backoff on top of their version. Files changed
+    /// by both sides fail the commit with [`Error::MergeConflict`], and
+    /// [`Error::CommitConflict`] is returned once the attempts are exhausted.
+    ///
+    /// Files in memory are not updated by the merge, reload the manifest
+    /// to see files committed by others.
+    pub async fn write_metadata(&self, manifest_path: &str) -> Result<()> {
+        let mut manifest = manifest_path.to_string();
+        for attempt in 0..COMMIT_ATTEMPTS {
+            let base = self.ctx.version();
+            if self.is_sealed(base).await? {
+                return Err(Error::Sealed { version: base }.into());
+            }
+
+            let metadata_content = self.encode_metadata(&manifest, base + 1).await?;
+            let res = self
+                .ctx
+                .op
+                .write_with(&self.ctx.metadata_path, metadata_content)
+                .if_match(&self.ctx.previous_etag())
+                .await;
+            match res {
+                Ok(written) => {
+                    self.ctx.update_etag(written).await?;
+                    *self.ctx.version.lock().unwrap() = base + 1;
+                    self.ctx.set_base_manifest(&manifest);
+                    return Ok(());
+                }
+                Err(err) if err.kind() == ErrorKind::ConditionNotMatch => {
+                    log::warn!("metadata changed by others, merging for attempt {attempt}");
+                    tokio::time::sleep(Duration::from_millis(100 << attempt)).await;
+                    manifest = self.merge_latest(&manifest).await?;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Err(Error::CommitConflict {
+            attempts: COMMIT_ATTEMPTS,
+        }
+        .into())
+    }
+
+    /// Merge our changes into the latest committed manifest.
+    ///
+    /// Returning the merged manifest id, the etag, version and base manifest
+    /// are refreshed so that the next commit is conditioned on the manifest
+    /// we merged into.
+    async fn merge_latest(&self, ours: &str) -> Result<String> {
+        // Stat before reading, so that changes after the stat will fail the
+        // next commit instead of being overwritten.
+        // The etag is only used to condition the next commit, metadata
+        // exists whenever the stat succeeds.
+        let (etag, theirs) = match self.ctx.op.stat(&self.ctx.metadata_path).await {
+            Ok(stat) => (
+                metadata_etag(&self.ctx.op, &stat)?,
+                Some(self.decode_metadata().await?),
+            ),
+            Err(err) if err.kind() == ErrorKind::NotFound => ("*".to_string(), None),
+            Err(err) => return Err(err.into()),
+        };
+        let (mut files, version, latest) = match theirs {
+            Some(theirs) => (
+                self.read_manifest_into(&theirs.manifest).await?,
+                theirs.version,
+                Some(theirs.manifest),
+            ),
+            None => (BTreeMap::new(), self.ctx.version(), None),
+        };
+        let base = match self.ctx.base_manifest() {
+            Some(base) => self.read_manifest_into(&base).await?,
+            None => BTreeMap::new(),
+        };
+        let ours = self.read_manifest_into(ours).await?;
+        merge_files(&base, &ours, &mut files)?;
+
+        let merged = self.write_files(&files).await?;
+        *self.ctx.previous_etag.lock().unwrap() = etag;
+        *self.ctx.version.lock().unwrap() = version;
+        *self.ctx.manifest.lock().unwrap() = latest;
+        Ok(merged)
+    }
+
+    /// Commit the current files as a new version.
+    ///
+    /// The manifest is written and the metadata is swapped to it with the
+    /// version bumped, returning the committed version. The etag is taken
+    /// from the write, so the same `Fs` could commit repeatedly.
+    ///
+    /// Files are reloaded from the committed manifest if our changes were
+    /// merged with others.
+    pub async fn commit(&mut self) -> Result<usize> {
+        let manifest = self.write_manifest().await?;
+        self.write_metadata(&manifest).await?;
+        if let Some(committed) = self.ctx.base_manifest().filter(|m| *m != manifest) {
+            self.files = self.read_manifest_into(&committed).await?;
+        }
+        Ok(self.ctx.version())
+    }
+
+    /// Stage the current files by writing their manifest.
+    ///
+    /// Only immutable chunks are written, so staging is idempotent and safe
+    /// to retry. Returning the manifest id to [`Fs::publish`].
+    pub async fn stage(&self) -> Result<String> {
+        self.write_manifest().await
+    }
+
+    /// Publish a staged manifest by swapping the metadata pointer.
+    ///
+    /// This is the single conditional write of a commit, so it could be
+    /// retried on conflicts without uploading chunks again. Changes merged
+    /// with other writers are not reflected in files in memory, reload them
+    /// by [`Fs::read_metadata`] and [`Fs::read_manifest`].
+    pub async fn publish(&self, manifest_id: &str) -> Result<()> {
+        self.write_metadata(manifest_id).await
+    }
+
+    /// Seal the given version to make it immutable.
+    ///
+    /// The current version or any retained one could be sealed. Its
+    /// manifest is recorded in the sentinel object, so that gc keeps its
+    /// chunks and [`Fs::load_version`] still finds it after it's dropped
+    /// from history.
+    ///
+    /// Commits on top of a sealed version are rejected with
+    /// [`Error::Sealed`], so sealing the current version freezes the file
+    /// system for good. Sealing an older version leaves later commits alone.
+    pub async fn seal(&self, version: usize) -> Result<()> {
+        let manifest = self
+            .snapshots()
+            .await?
+            .into_iter()
+            .find(|snapshot| snapshot.version == version)
+            .ok_or_else(|| anyhow!("version {version} is not retained"))?
+            .manifest;
+        self.ctx
+            .op
+            .write(&self.ctx.sealed_path(version), manifest.into_bytes())
+            .await?;
+        Ok(())
+    }
+
+    /// The manifest recorded by sealing the given version, `None` if it's
+    /// not sealed.
+    async fn sealed_manifest(&self, version: usize) -> Result<Option<String>> {
+        match self.ctx.op.read(&self.ctx.sealed_path(version)).await {
+            Ok(buf) => Ok(Some(String::from_utf8(buf.to_vec())?)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Check whether the given version has been sealed.
+    pub async fn is_sealed(&self, version: usize) -> Result<bool> {
+        match self.ctx.op.stat(&self.ctx.sealed_path(version)).await {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Encode the metadata of the version pointing to the given manifest.
+    ///
+    /// The current snapshot is pushed into history if versions are retained.
+    async fn encode_metadata(&self, manifest_path: &str, version: usize) -> Result<Buffer> {
+        let retain = self.ctx.config.retain_versions as usize;
+        let mut history = Vec::new();
+        if retain > 0 {
+            if let Some(prev) = self.try_decode_metadata().await? {
+                history.push(specs_v2::Snapshot {
+                    version: prev.version,
+                    manifest: prev.manifest,
+                    last_modified: prev.last_modified,
+                });
+                history.extend(prev.history);
+                history.truncate(retain);
+            }
+        }
+
+        let metadata = specs_v2::Metadata {
+            version,
+            manifest: manifest_path.to_string(),
+            last_modified: Utc::now().timestamp() as u64,
+            config: self.ctx.config.clone(),
+            history,
+        };
+        let mut content = specs_v2::METADATA_MAGIC.to_vec();
+        bincode::encode_into_std_write(metadata, &mut content, bincode::config::standard())?;
+        let content = Buffer::from(content);
+        match &self.ctx.cipher {
+            Some(cipher) => {
+                let mut sealed = ENCRYPTED_METADATA_MAGIC.to_vec();
+                sealed.extend_from_slice(&cipher.encrypt(content, METADATA_AAD)?.to_vec());
+                Ok(sealed.into())
+            }
+            None => Ok(content),
+        }
+    }
+
+    /// The effective configuration of the file system.
+    pub fn info(&self) -> FsInfo {
+        let config = &self.ctx.config;
+        FsInfo {
+            spec_version: 2,
+            version: self.ctx.version(),
+            chunk_size: config.chunk_size,
+            hasher: config.hasher.clone(),
+            id_encoding: config.id_encoding.clone(),
+            compression: config.compression.clone(),
+            encryption: config.encryption.clone(),
+            retain_versions: config.retain_versions,
+        }
+    }
+
+    /// Check whether the metadata still matches the etag we loaded.
+    ///
+    /// This is a cheap `stat` call that lets callers bail out before
+    /// uploading chunks for a commit that will fail the `if_match`
+    /// precondition anyway. Services without etags compare the version of
+    /// metadata instead.
+    pub async fn check_commit_ready(&self) -> Result<bool> {
+        match self.ctx.op.stat(&self.ctx.metadata_path).await {
+            Ok(stat) => match stat.etag() {
+                Some(etag) => Ok(etag == self.ctx.previous_etag()),
+                None => Ok(self.decode_metadata().await?.version == self.ctx.version()),
+            },
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(self.ctx.previous_etag() == "*"),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub async fn read_metadata(&self) -> Result<String> {
+        let metadata = self.decode_metadata().await?;
+
+        if metadata.version != self.ctx.version() {
+            return Err(anyhow!("metadata version mismatch"));
+        }
+        Ok(metadata.manifest)
+    }
+
+    /// Load the files of the given version from metadata, its history or
+    /// sealed versions.
+    pub async fn load_version(&mut self, version: usize) -> Result<()> {
+        let retained = self
+            .snapshots()
+            .await?
+            .into_iter()
+            .find(|snapshot| snapshot.version == version)
+            .map(|snapshot| snapshot.manifest);
+        let manifest = match retained {
+            Some(manifest) => manifest,
+            None => self
+                .sealed_manifest(version)
+                .await?
+                .ok_or_else(|| anyhow!("version {version} is not retained"))?,
+        };
+        self.read_manifest(&manifest).await
+    }
+
+    /// Load the files of the latest version committed at or before `when`.
+    pub async fn load_as_of(&mut self, when: DateTime<Utc>) -> Result<()> {
+        let ts = when.timestamp().max(0) as u64;
+        let manifest = self
+            .snapshots()
+            .await?
+            .into_iter()
+            .find(|snapshot| snapshot.last_modified <= ts)
+            .ok_or_else(|| anyhow!("no version is retained as of {when}"))?
+            .manifest;
+        self.read_manifest(&manifest).await
+    }
+
+    /// The current snapshot followed by retained ones, the newest first.
+    async fn snapshots(&self) -> Result<Vec<specs_v2::Snapshot>> {
+        let metadata = self.decode_metadata().await?;
+        let mut snapshots = vec![specs_v2::Snapshot {
+            version: metadata.version,
+            manifest: metadata.manifest,
+            last_modified: metadata.last_modified,
+        }];
+        snapshots.extend(metadata.history);
+        Ok(snapshots)
+    }
+
+    /// Read and decode the metadata, returns `None` if not exists.
+    async fn try_decode_metadata(&self) -> Result<Option<specs_v2::Metadata>> {
+        match self.decode_metadata().await {
+            Ok(metadata) => Ok(Some(metadata)),
+            Err(err)
+                if err
+                    .downcast_ref::<opendal::Error>()
+                    .is_some_and(|err| err.kind() == ErrorKind::NotFound) =>
+            {
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Read and decode the metadata without any check.
+    async fn decode_metadata(&self) -> Result<specs_v2::Metadata> {
+        let metadata_content = self.ctx.op.read(&self.ctx.metadata_path).await?;
+        decode_metadata_content(metadata_content, self.ctx.cipher.as_ref())
+    }
+
+    /// Verify the whole chain of metadata, manifest and chunks.
+    ///
+    /// Chunks are downloaded and rehashed if `verify_hashes` is true,
+    /// otherwise only their existence is checked.
+    pub async fn verify_all(&self, verify_hashes: bool) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+
+        let metadata = match self.decode_metadata().await {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                report.metadata_error = Some(err.to_string());
+                return Ok(report);
+            }
+        };
+        let files = match self.read_manifest_into(&metadata.manifest).await {
+            Ok(files) => files,
+            Err(err) => {
+                report.manifest_error = Some(format!("manifest {}: {err}", metadata.manifest));
+                return Ok(report);
+            }
+        };
+        if let Err(err) = validate_files(&files) {
+            report.manifest_error = Some(err.to_string());
+            return Ok(report);
+        }
+
+        let opts = VerifyOptions::new().deep(verify_hashes);
+        self.verify_chunks(&files, opts, &mut report).await?;
+        Ok(report)
+    }
+
+    /// Verify that chunks of current files are intact.
+    ///
+    /// Only existence of chunks is checked in shallow mode, and chunks are
+    /// downloaded and rehashed in deep mode. Files without chunks are always
+    /// valid.
+    pub async fn verify(&self, opts: VerifyOptions) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        self.verify_chunks(&self.files, opts, &mut report).await?;
+        Ok(report)
+    }
+
+    /// Delete chunks that are not referenced by any manifest.
+    ///
+    /// See [`Fs::gc_retaining`] for details.
+    pub async fn gc(&self) -> Result<GcReport> {
+        self.gc_retaining(&[]).await
+    }
+
+    /// Delete chunks that are not referenced by any manifest, keeping the
+    /// chunks of `retained` manifests as well.
+    ///
+    /// Live chunks are the ones used by current files, by the manifest in
+    /// metadata and its retained history, by sealed manifests, or by pins
+    /// not yet removed by [`Fs::unpin`], manifest and pin chunks themselves
+    /// are live too. Objects that are not well-formed chunk ids are never
+    /// deleted.
+    ///
+    /// Chunks uploaded by writers that haven't been committed look the same
+    /// as unreferenced ones, so don't run gc alongside writers.
+    pub async fn gc_retaining(&self, retained: &[String]) -> Result<GcReport> {
+        let live = self.live_chunks(retained).await?;
+
+        let mut report = GcReport::default();
+        let mut batch = Vec::with_capacity(GC_BATCH_SIZE);
+        let mut chunks = self.ctx.list_chunks();
+        while let Some(chunk_id) = chunks.try_next().await? {
+            if live.contains(&chunk_id) {
+                report.live_chunks += 1;
+                continue;
+            }
+            if !self.ctx.hasher.is_valid_id(&chunk_id) {
+                continue;
+            }
+            report.reclaimed_bytes += self.ctx.store.size(&chunk_id).await?.unwrap_or(0);
+            batch.push(chunk_id);
+            if batch.len() >= GC_BATCH_SIZE {
+                self.ctx.store.delete_batch(&batch).await?;
+                report.deleted_chunks += batch.len();
+                batch.clear();
+            }
+        }
+        if !batch.is_empty() {
+            self.ctx.store.delete_batch(&batch).await?;
+            report.deleted_chunks += batch.len();
+        }
+        Ok(report)
+    }
+
+    /// The chunks kept by gc, see [`Fs::gc_retaining`].
+    async fn live_chunks(&self, retained: &[String]) -> Result<BTreeSet<String>> {
+        let mut manifests: Vec<String> = retained.to_vec();
+        if let Some(metadata) = self.try_decode_metadata().await? {
+            manifests.push(metadata.manifest);
+            manifests.extend(metadata.history.into_iter().map(|s| s.manifest));
+        }
+        let sealed_root = format!("{}/", self.ctx.sealed_path);
+        let mut sealed = self.ctx.op.lister(&sealed_root).await?;
+        while let Some(entry) = sealed.try_next().await? {
+            if entry.metadata().is_file() {
+                let manifest = self.ctx.op.read(entry.path()).await?.to_vec();
+                manifests.push(String::from_utf8(manifest)?);
+            }
+        }
+
+        let mut live: BTreeSet<String> = self
+            .files
+            .values()
+            .flat_map(|file| file.chunks().iter().cloned())
+            .collect();
+        for manifest in manifests {
+            let files = self.read_manifest_into(&manifest).await?;
+            live.extend(
+                files
+                    .values()
+                    .flat_map(|file| file.chunks().iter().cloned()),
+            );
+            live.extend(self.manifest_segments(&manifest).await?);
+            live.insert(manifest);
+        }
+        for pin in self.pins().await? {
+            live.extend(self.read_pin(&pin).await?.chunks().iter().cloned());
+            live.insert(pin);
+        }
+        Ok(live)
+    }
+
+    /// Audit all objects in the chunk storage.
+    ///
+    /// Chunks are referenced if they're live for [`Fs::gc`], so
+    /// unreferenced chunks are exactly the ones gc would delete. The content
+    /// of valid chunks is rehashed if `verify_hashes` is true.
+    pub async fn audit_storage(&self, verify_hashes: bool) -> Result<StorageAudit> {
+        let live = self.live_chunks(&[]).await?;
+
+        let mut audit = StorageAudit::default();
+        let mut chunks = self.ctx.list_chunks();
+        while let Some(name) = chunks.try_next().await? {
+            if !self.ctx.hasher.is_valid_id(&name) {
+                audit.foreign.push(name);
+                continue;
+            }
+            if verify_hashes && !self.ctx.stored_chunk_matches(&name).await? {
+                audit.mismatched.push(name);
+                continue;
+            }
+            if live.contains(&name) {
+                audit.referenced.push(name);
+            } else {
+                audit.unreferenced.push(name);
+            }
+        }
+        Ok(audit)
+    }
+
+    /// Verify all chunks referenced by files, the result will be recorded
+    /// in the report.
+    ///
+    /// Every distinct chunk is checked once, up to `opts.concurrency` at the
+    /// same time.
+    async fn verify_chunks(
+        &self,
+        files: &BTreeMap<String, File>,
+        opts: VerifyOptions,
+        report: &mut VerifyReport,
+    ) -> Result<()> {
+        #[derive(Clone, Copy, PartialEq)]
+        enum State {
+            Ok,
+            Missing,
+            Mismatched,
+        }
+
+        let chunks: BTreeSet<&str> = files
+            .values()
+            .flat_map(|file| file.chunks())
+            .map(String::as_str)
+            .collect();
+        let checked: BTreeMap<&str, State> = stream::iter(chunks)
+            .map(|chunk_id| async move {
+                let state = if opts.deep {
+                    match self.ctx.store.get(chunk_id).await? {
+                        None => State::Missing,
+                        Some(buf) => match self.ctx.stored_content_matches(chunk_id, buf).await? {
+                            true => State::Ok,
+                            false => State::Mismatched,
+                        },
+                    }
+                } else if self.ctx.store.exists(chunk_id).await? {
+                    State::Ok
+                } else {
+                    State::Missing
+                };
+                Ok::<_, anyhow::Error>((chunk_id, state))
+            })
+            .buffer_unordered(opts.concurrency)
+            .try_collect()
+            .await?;
+
+        for file in files.values() {
+            for chunk_id in file.chunks() {
+                let group = match checked[chunk_id.as_str()] {
+                    State::Ok => continue,
+                    State::Missing => &mut report.missing_chunks,
+                    State::Mismatched => &mut report.mismatched_chunks,
+                };
+                group
+                    .entry(file.path().to_string())
+                    .or_default()
+                    .push(chunk_id.clone());
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn load_from(&mut self, external: Operator) -> Result<()> {
+        self.load_from_with(external, LoadOptions::default())
+            .await?;
+        Ok(())
+    }
+
+    /// Load all files from the external storage with given options.
+    ///
+    /// The storage is listed recursively and directories are skipped, files
+    /// keep their full relative path. Returning a report of loaded and
+    /// skipped files.
+    pub async fn load_from_with(
+        &mut self,
+        external: Operator,
+        opts: LoadOptions,
+    ) -> Result<LoadReport> {
+        let mut report = LoadReport::default();
+        let mut lister = external.lister_with("/").recursive(true).await?;
+
+        while let Some(entry) = lister.next().await.transpose()? {
+            if !entry.metadata().mode().is_file() || !opts.accept(&entry) {
+                continue;
+            }
+            self.load_path(&external, entry.path(), &opts, &mut report)
+                .await?;
+        }
+
+        let manifest = self.write_manifest().await?;
+        self.write_metadata(&manifest).await?;
+        Ok(report)
+    }
+
+    /// Load files at given paths from the external storage.
+    ///
+    /// Files are read directly without listing, so this works with storages
+    /// that don't support list.
+    pub async fn load_paths(&mut self, external: Operator, paths: &[String]) -> Result<()> {
+        self.load_paths_with(external, paths, LoadOptions::default())
+            .await?;
+        Ok(())
+    }
+
+    /// Load files at given paths from the external storage with given options.
+    ///
+    /// The entry filter of options is not consulted since there is no listed
+    /// entry for the paths.
+    pub async fn load_paths_with(
+        &mut self,
+        external: Operator,
+        paths: &[String],
+        opts: LoadOptions,
+    ) -> Result<LoadReport> {
+        let mut report = LoadReport::default();
+        for path in paths {
+            self.load_path(&external, path, &opts, &mut report).await?;
+        }
+
+        let manifest = self.write_manifest().await?;
+        self.write_metadata(&manifest).await?;
+        Ok(report)
+    }
+
+    /// Load a single path following the error policy of options.
+    async fn load_path(
+        &mut self,
+        external: &Operator,
+        path: &str,
+        opts: &LoadOptions,
+        report: &mut LoadReport,
+    ) -> Result<()> {
+        let target = opts.map_path(path);
+
+        let mut attempts = 0;
+        let res = loop {
+            match self.load_file(external, path, &target).await {
+                Err(err) if matches!(opts.on_error, OnError::Retry(n) if attempts < n) => {
+                    attempts += 1;
+                    log::warn!("failed to load file {path}, retry {attempts}: {err}");
+                }
+                res => break res,
+            }
+        };
+
+        match res {
+            Ok(file) => {
+                self.insert_file(file);
+                report.loaded.push(path.to_string());
+            }
+            Err(err) if opts.on_error == OnError::Skip => {
+                log::warn!("failed to load file {path}, skipped: {err}");
+                report.failed.push((path.to_string(), err));
+            }
+            Err(err) => return Err(err),
+        }
+        Ok(())
+    }
+
+    /// Load a single file from the external storage into `target`.
+    async fn load_file(&self, external: &Operator, path: &str, target: &str) -> Result<File> {
+        let stream = external
+            .reader_with(path)
+            .chunk(self.ctx.chunk_size())
+            .concurrent(self.ctx.read_concurrency())
+            .await?
+            .into_stream(..)
+            .await?;
+        let mut writer = self.new_file_writer(target);
+        writer.write_from_stream(stream).await?;
+        Ok(writer.close().await?.file)
+    }
+}
+
+/// Validate the invariants of files decoded from a manifest.
+fn validate_files(files: &BTreeMap<String, File>) -> Result<()> {
+    for (path, file) in files {
+        if path.is_empty() {
+            return Err(anyhow!("file with empty path"));
+        }
+        match file.inline() {
+            Some(_) if !file.chunks().is_empty() => {
+                return Err(anyhow!("file {path} has both inline content and chunks"));
+            }
+            Some(inline) if inline.len() as u64 != file.size() => {
+                return Err(anyhow!(
+                    "file {path} has size {} but inline content of {} bytes",
+                    file.size(),
+                    inline.len()
+                ));
+            }
+            None if file.chunks().is_empty() && file.size() != 0 => {
+                return Err(anyhow!(
+                    "file {path} has size {} but no chunks",
+                    file.size()
+                ));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Calculate the merkle leaf hash of a file.
+///
+/// All variable length fields are length prefixed to avoid ambiguity.
+fn merkle_leaf(file: &File) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[0]);
+    hasher.update(&(file.path().len() as u64).to_le_bytes());
+    hasher.update(file.path().as_bytes());
+    hasher.update(&(file.chunks().len() as u64).to_le_bytes());
+    for chunk_id in file.chunks() {
+        hasher.update(&(chunk_id.len() as u64).to_le_bytes());
+        hasher.update(chunk_id.as_bytes());
+    }
+    hasher.update(&file.size().to_le_bytes());
+    if let Some(inline) = file.inline() {
+        hasher.update(inline);
+    }
+    hasher.finalize()
+}
+
+/// Check whether the segment may contain paths starting with `prefix`.
+fn segment_covers(segment: &specs_v2::Segment, prefix: &str) -> bool {
+    segment.last.as_str() >= prefix
+        && (segment.first.as_str() <= prefix || segment.first.starts_with(prefix))
+}
+
+/// Decode the segments of the manifest index if the content is one.
+fn decode_manifest_index(content: Buffer) -> Result<Option<Vec<specs_v2::Segment>>> {
+    let content = content.to_bytes();
+    let Some(index) = content.strip_prefix(specs_v2::MANIFEST_INDEX_MAGIC) else {
+        return Ok(None);
+    };
+    let (index, _): (specs_v2::ManifestIndex, _) =
+        bincode::decode_from_slice(index, bincode::config::standard())?;
+    Ok(Some(index.segments))
+}
+
+/// Apply our changes from `base` to `ours` onto `theirs`.
+///
+/// Paths changed by us are taken from ours, and paths changed by both sides
+/// to different results are conflicts.
+fn merge_files(
+    base: &BTreeMap<String, File>,
+    ours: &BTreeMap<String, File>,
+    theirs: &mut BTreeMap<String, File>,
+) -> Result<()> {
+    for path in base.keys().chain(ours.keys()) {
+        let (b, o) = (base.get(path), ours.get(path));
+        if b == o {
+            continue;
+        }
+        let t = theirs.get(path);
+        if t != b && t != o {
+            return Err(Error::MergeConflict { path: path.clone() }.into());
+        }
+        match o {
+            Some(file) => theirs.insert(path.clone(), file.clone()),
+            None => theirs.remove(path),
+        };
+    }
+    Ok(())
 }
 
-/// Calculate the chunk id from the buffer.
-///
-/// The chunk id is the URL_SAFE_NO_PAD base64 of blake3 hash of the buffer content.
-///
-/// chund id is used to identify the chunk in the storage. If the chunk
-/// id already exists, we can reuse the existing chunk instead of creating
-/// a new one.
-fn chunk_id(bs: Buffer) -> String {
-    let mut hasher = blake3::Hasher::new();
-    for b in bs {
-        hasher.update(&b);
+/// The associated data of an encrypted chunk, binding it to the chunk id and
+/// the flags of its header.
+fn chunk_aad(chunk_id: &str, flags: u8) -> Vec<u8> {
+    let mut aad = chunk_id.as_bytes().to_vec();
+    aad.push(flags);
+    aad
+}
+
+/// Feed the buffer to the hash state without blocking the async executor.
+///
+/// Large buffers are hashed by `spawn_blocking` if running inside a tokio
+/// runtime, otherwise they're hashed in place.
+pub(crate) async fn update_nonblocking(
+    mut state: Box<dyn HashState>,
+    buf: Buffer,
+) -> Result<Box<dyn HashState>> {
+    let blocking = buf.len() >= BLOCKING_HASH_SIZE;
+    let update = move || {
+        state.update_buffer(&buf);
+        state
+    };
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if blocking => Ok(handle.spawn_blocking(update).await?),
+        _ => Ok(update()),
+    }
+}
+
+/// The etag of existing metadata to condition the next commit on.
+///
+/// Services without conditional write can't protect commits anyway, so we
+/// don't require etag for them.
+fn metadata_etag(op: &Operator, stat: &opendal::Metadata) -> Result<String> {
+    match stat.etag() {
+        Some(etag) => Ok(etag.to_string()),
+        None if !op.info().full_capability().write_with_if_match => {
+            log::warn!("input storage services doesn't support conditional write, concurrent commits are not protected");
+            Ok("*".to_string())
+        }
+        None => Err(anyhow!(
+            "input storage services doesn't have etag: {:?}",
+            op.info()
+        )),
+    }
+}
+
+/// Decode the metadata content, decrypting it with the cipher if it's
+/// encrypted.
+///
+/// Metadata of spec v1 is upgraded with the config it was written by.
+fn decode_metadata_content(content: Buffer, cipher: Option<&Cipher>) -> Result<specs_v2::Metadata> {
+    let content = content.to_bytes();
+    let content = match (content.strip_prefix(ENCRYPTED_METADATA_MAGIC), cipher) {
+        (Some(sealed), Some(cipher)) => cipher
+            .decrypt(Buffer::from(sealed.to_vec()), METADATA_AAD)?
+            .to_bytes(),
+        (Some(_), None) => return Err(anyhow!("file system is encrypted, but no key is given")),
+        (None, Some(_)) => return Err(anyhow!("file system is not encrypted, but a key is given")),
+        (None, None) => content,
+    };
+    let metadata = match content.strip_prefix(specs_v2::METADATA_MAGIC) {
+        Some(content) => bincode::decode_from_slice(content, bincode::config::standard())?.0,
+        None => {
+            let (metadata, _): (specs_v1::Metadata, _) =
+                bincode::decode_from_slice(&content, bincode::config::standard())?;
+            metadata.into()
+        }
+    };
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch::PatchFile;
+    use crate::test_util::{conditional_memory, memory, noise, text, MemoryChunkStore};
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn test_compression_probe() -> Result<()> {
+        let op = memory();
+        let fs = Fs::builder(op.clone())
+            .compression(Codec::Zstd { level: 3 })
+            .build()
+            .await?;
+
+        let random = Buffer::from(noise(512 * 1024));
+        let random_id = fs.ctx.write_chunk(random.clone()).await?;
+        let stored = op.read(&format!("data/{random_id}")).await?;
+        assert_eq!(stored.to_bytes()[0], Codec::None.tag());
+        assert!(stored.len() <= random.len() + 16);
+
+        let text = Buffer::from(text(512 * 1024));
+        let text_id = fs.ctx.write_chunk(text.clone()).await?;
+        let stored = op.read(&format!("data/{text_id}")).await?;
+        assert_eq!(stored.to_bytes()[0], Codec::Zstd { level: 3 }.tag());
+        assert!(stored.len() < text.len() / 2);
+
+        assert_eq!(
+            fs.ctx.read_chunk(&random_id).await?.to_vec(),
+            random.to_vec()
+        );
+        assert_eq!(fs.ctx.read_chunk(&text_id).await?.to_vec(), text.to_vec());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compression_dedup() -> Result<()> {
+        let op = memory();
+        let content = Buffer::from(text(100 * 1024));
+        let fs = Fs::builder(op.clone()).build().await?;
+        let mut w = fs.new_file_writer("a.txt");
+        w.write(content.clone()).await?;
+        assert_eq!(w.close().await?.new_chunks, 1);
+
+        // Chunks stored uncompressed are reused by compressed writes, and
+        // chunk ids are hashed over the uncompressed content.
+        let fs = Fs::builder(op.clone())
+            .compression(Codec::Zstd { level: 3 })
+            .build()
+            .await?;
+        let mut w = fs.new_streaming_writer("b.txt");
+        w.write(content.clone()).await?;
+        let result = w.close().await?;
+        assert_eq!((result.new_chunks, result.dedup_chunks), (0, 1));
+        assert_eq!(result.file.chunks()[0], Blake3Hasher.chunk_id(&content));
+
+        let mut w = fs.new_streaming_writer("c.txt");
+        w.write(Buffer::from(text(50 * 1024))).await?;
+        let chunk_id = w.close().await?.file.chunks()[0].clone();
+        let stored = op.read(&format!("data/{chunk_id}")).await?;
+        assert_eq!(stored.to_bytes()[0], Codec::Zstd { level: 3 }.tag());
+        assert_eq!(
+            fs.ctx.read_chunk(&chunk_id).await?.to_vec(),
+            text(50 * 1024)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_mixed_compression() -> Result<()> {
+        let op = memory();
+        let content = text(64 * 1024);
+        let plain = Fs::builder(op.clone()).build().await?;
+        let zstd = Fs::builder(op.clone())
+            .compression(Codec::Zstd { level: 3 })
+            .build()
+            .await?;
+
+        // Chunks of the file are written by different codecs.
+        let mut chunks = vec![];
+        for (idx, part) in content.chunks(16 * 1024).enumerate() {
+            let ctx = if idx % 2 == 0 { &plain.ctx } else { &zstd.ctx };
+            chunks.push(ctx.write_chunk(Buffer::from(part.to_vec())).await?);
+        }
+        let mut tags = vec![];
+        for chunk_id in &chunks {
+            tags.push(op.read(&format!("data/{chunk_id}")).await?.to_bytes()[0]);
+        }
+        assert_eq!(tags, [0, 1, 0, 1]);
+
+        // Every chunk is decoded by its own header, whatever the file system
+        // is configured with.
+        for mut fs in [plain, zstd] {
+            let file = File::new(
+                "a.txt".to_string(),
+                chunks.clone(),
+                vec![16 * 1024; 4],
+                vec![],
+                Utc::now(),
+            );
+            fs.insert_file(file);
+            assert_eq!(fs.read("a.txt").await?.to_vec(), content);
+            let bufs = fs.open("a.txt")?.read_ranges(&[(10_000, 40_000)]).await?;
+            assert_eq!(bufs[0].to_vec(), content[10_000..40_000]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stored_sizes() -> Result<()> {
+        let op = memory();
+        let content = Buffer::from(text(64 * 1024));
+        let mut fs = Fs::builder(op.clone())
+            .chunk_size(16 * 1024)
+            .compression(Codec::Zstd { level: 3 })
+            .build()
+            .await?;
+        fs.replace_file("a.txt", content.clone()).await?;
+        fs.replace_file("b.txt", content.clone()).await?;
+        let manifest = fs.write_manifest().await?;
+
+        // Stored sizes are persisted in the manifest.
+        let files = fs.read_manifest_into(&manifest).await?;
+        let file = &files["a.txt"];
+        let mut stored = 0;
+        for chunk_id in file.chunks() {
+            stored += op.stat(&format!("data/{chunk_id}")).await?.content_length();
+        }
+        assert_eq!(file.stored_size(), Some(stored));
+        assert!(stored < file.size() / 2);
+        // Shared chunks are counted once.
+        assert_eq!(fs.physical_size().await?, stored);
+
+        // Offsets still use the logical sizes.
+        let reader = fs.open("a.txt")?;
+        assert_eq!(reader.chunk_index(16 * 1024), Some(1));
+        let bufs = reader.read_ranges(&[(16_000, 33_000)]).await?;
+        assert_eq!(bufs[0].to_vec(), content.slice(16_000..33_000).to_vec());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stored_size_mismatch() -> Result<()> {
+        let op = memory();
+        let mut fs = Fs::builder(op.clone()).build().await?;
+        let file = fs.replace_file("a.txt", Buffer::from("hello")).await?;
+        assert_eq!(file.stored_size(), Some(6));
+        op.write(&format!("data/{}", file.chunks()[0]), vec![0u8, b'h'])
+            .await?;
+        assert!(fs.read("a.txt").await.is_err());
+
+        // Chunks without recorded stored sizes are stat from the storage.
+        let file = File::new(
+            "b.txt".to_string(),
+            file.chunks().to_vec(),
+            vec![5],
+            vec![],
+            Utc::now(),
+        );
+        assert_eq!(file.stored_size(), None);
+        fs.files.clear();
+        fs.insert_file(file);
+        assert_eq!(fs.physical_size().await?, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_all_and_stream_check_sizes() -> Result<()> {
+        let op = memory();
+        let mut fs = Fs::builder(op.clone()).build().await?;
+        let content = noise(3000);
+        let mut chunks = vec![];
+        for part in content.chunks(1024) {
+            chunks.push(fs.ctx.write_chunk(Buffer::from(part.to_vec())).await?);
+        }
+        let file = File::new(
+            "a".to_string(),
+            chunks,
+            vec![1024, 1024, 952],
+            vec![],
+            Utc::now(),
+        );
+        fs.insert_file(file.clone());
+        let streamed: Vec<Buffer> = fs.open("a")?.into_stream().try_collect().await?;
+        let streamed: Buffer = streamed.into_iter().flatten().collect();
+        assert_eq!(streamed.to_vec(), content);
+
+        // The last chunk is swapped for a longer one.
+        let other = fs.ctx.write_chunk(Buffer::from(noise(2048))).await?;
+        let mut entry: specs_v2::File = file.clone().into();
+        entry.path = "wrong_chunk".to_string();
+        entry.chunks[2] = other;
+        fs.insert_file(entry.into());
+        let err = fs.open("wrong_chunk")?.read_all().await.unwrap_err();
+        assert!(err.to_string().contains("expect 952"), "{err}");
+        let res: Result<Vec<Buffer>> = fs.open("wrong_chunk")?.into_stream().try_collect().await;
+        assert!(res.unwrap_err().to_string().contains("expect 952"));
+
+        // Chunk sizes not summing to the file size.
+        let mut entry: specs_v2::File = file.clone().into();
+        entry.path = "wrong_size".to_string();
+        entry.size = 2999;
+        fs.insert_file(entry.into());
+        let err = fs.open("wrong_size")?.read_all().await.unwrap_err();
+        assert!(err.to_string().contains("chunks sum to 3000"), "{err}");
+        let mut stream = fs.open("wrong_size")?.into_stream().boxed();
+        assert!(stream.next().await.unwrap().is_err());
+        assert!(stream.next().await.is_none());
+
+        // Missing chunks are reported by id.
+        op.delete(&format!("data/{}", file.chunks()[1])).await?;
+        let err = fs.open("a")?.read_all().await.unwrap_err();
+        assert!(err.to_string().contains("missing from storage"), "{err}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_zstd_compression() -> Result<()> {
+        let op = memory();
+        let mut fs = Fs::builder(op.clone())
+            .chunk_size(64 * 1024)
+            .compression(Codec::Zstd { level: 3 })
+            .build()
+            .await?;
+        assert_eq!(fs.info().compression.as_deref(), Some("zstd"));
+        let content = Buffer::from(text(200 * 1024));
+        let file = fs.replace_file("a.txt", content.clone()).await?;
+        let manifest = fs.write_manifest().await?;
+        fs.write_metadata(&manifest).await?;
+
+        // Chunk ids are hashed over the uncompressed content.
+        let first = &file.chunks()[0];
+        assert_eq!(first, &Blake3Hasher.chunk_id(&content.slice(..64 * 1024)));
+        let stored = op.read(&format!("data/{first}")).await?;
+        assert!(stored.len() < 64 * 1024 / 2);
+        assert_eq!(stored.to_bytes()[0], Codec::Zstd { level: 3 }.tag());
+
+        // The codec is recorded in metadata and adopted by later opens.
+        let mut fs = Fs::create(op.clone()).await?;
+        assert_eq!(fs.info().compression.as_deref(), Some("zstd"));
+        assert_eq!(fs.ctx.codec, Codec::Zstd { level: 3 });
+        let manifest = fs.read_metadata().await?;
+        fs.read_manifest(&manifest).await?;
+        assert_eq!(fs.read("a.txt").await?.to_vec(), content.to_vec());
+
+        // The builder overrides the recorded codec.
+        let fs = Fs::builder(op).compression(Codec::None).build().await?;
+        assert_eq!(fs.info().compression, None);
+        assert_eq!(fs.ctx.codec, Codec::None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_and_stat() -> Result<()> {
+        let mut fs = Fs::builder(memory()).chunk_size(1024).build().await?;
+        let before = Utc::now().timestamp();
+        fs.replace_file("dir/b", Buffer::from(noise(2500))).await?;
+        fs.replace_file("a", Buffer::from("a")).await?;
+        fs.replace_file("empty", Buffer::new()).await?;
+
+        let paths: Vec<_> = fs.list().map(|file| file.path()).collect();
+        assert_eq!(paths, vec!["a", "dir/b", "empty"]);
+        let sizes: Vec<_> = fs.list().map(|file| file.size()).collect();
+        assert_eq!(sizes, vec![1, 2500, 0]);
+
+        let b = fs.stat("dir/b").unwrap();
+        assert_eq!(b.chunks().len(), 3);
+        assert!(b.last_modified().timestamp() >= before);
+        assert!(fs.stat("empty").unwrap().chunks().is_empty());
+        assert!(fs.stat("dir").is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stored_sizes_of_encrypted_chunks() -> Result<()> {
+        let fs = Fs::builder(memory())
+            .chunk_size(1024)
+            .encryption_key([7; 32])
+            .build()
+            .await?;
+        let mut w = fs.new_file_writer("a.bin");
+        w.write(Buffer::from(noise(2500))).await?;
+        let file = w.close().await?.file;
+        // Every chunk has a header byte and the overhead of the cipher.
+        let overhead = 1 + Cipher::OVERHEAD as u64;
+        assert_eq!(file.stored_size(), Some(2500 + 3 * overhead));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_info_after_reopen() -> Result<()> {
+        let op = memory();
+        let mut fs = Fs::builder(op.clone())
+            .chunk_size(1024)
+            .retain_versions(3)
+            .build()
+            .await?;
+        fs.commit().await?;
+
+        let fs = Fs::create(op).await?;
+        assert_eq!(
+            fs.info(),
+            FsInfo {
+                spec_version: 2,
+                version: 1,
+                chunk_size: 1024,
+                hasher: "blake3".to_string(),
+                id_encoding: "base64-url-nopad".to_string(),
+                compression: None,
+                encryption: None,
+                retain_versions: 3,
+            }
+        );
+        Ok(())
+    }
+
+    /// Write a file system in the layout of spec v1 by hand.
+    async fn write_spec_v1(op: &Operator, files: &[(&str, &[u8])]) -> Result<()> {
+        let mut entries = vec![];
+        for (path, content) in files {
+            let chunk_id = Blake3Hasher.chunk_id(&Buffer::from(content.to_vec()));
+            op.write(&format!("data/{chunk_id}"), content.to_vec())
+                .await?;
+            entries.push(specs_v1::File {
+                path: path.to_string(),
+                chunks: vec![chunk_id],
+                size: content.len() as u64,
+                last_modified: 1_700_000_000,
+            });
+        }
+        let manifest = specs_v1::Manifest { files: entries };
+        let manifest = bincode::encode_to_vec(manifest, bincode::config::standard())?;
+        let manifest_id = Blake3Hasher.chunk_id(&Buffer::from(manifest.clone()));
+        op.write(&format!("data/{manifest_id}"), manifest).await?;
+
+        let metadata = specs_v1::Metadata {
+            version: 0,
+            manifest: manifest_id,
+            last_modified: 1_700_000_000,
+        };
+        let metadata = bincode::encode_to_vec(metadata, bincode::config::standard())?;
+        op.write("metadata", metadata).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_spec_v1_file_system() -> Result<()> {
+        let op = memory();
+        write_spec_v1(&op, &[("a.txt", b"hello"), ("dir/b.txt", b"world")]).await?;
+
+        let mut fs = Fs::create(op.clone()).await?;
+        assert_eq!(fs.info().chunk_size, crate::DEFAULT_CHUNK_SIZE as u64);
+        let manifest = fs.read_metadata().await?;
+        fs.read_manifest(&manifest).await?;
+        assert_eq!(fs.len(), 2);
+        assert_eq!(fs.read("a.txt").await?.to_vec(), b"hello");
+        assert_eq!(fs.read_range("dir/b.txt", 1..3).await?.to_vec(), b"or");
+        assert!(fs.verify_all(true).await?.is_ok());
+
+        // The next commit upgrades the metadata to spec v2.
+        fs.replace_file("c.txt", Buffer::from("!")).await?;
+        assert_eq!(fs.commit().await?, 1);
+        let content = op.read("metadata").await?.to_vec();
+        assert!(content.starts_with(specs_v2::METADATA_MAGIC));
+
+        let mut fs = Fs::create(op).await?;
+        let manifest = fs.read_metadata().await?;
+        fs.read_manifest(&manifest).await?;
+        assert_eq!(fs.read("a.txt").await?.to_vec(), b"hello");
+        assert_eq!(fs.read("c.txt").await?.to_vec(), b"!");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compression_rejected_by_spec_v1() -> Result<()> {
+        let op = memory();
+        write_spec_v1(&op, &[("a.txt", b"hello")]).await?;
+        let result = Fs::builder(op)
+            .compression(Codec::Zstd { level: 3 })
+            .build()
+            .await;
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_physical_size_of_spec_v1() -> Result<()> {
+        let op = memory();
+        write_spec_v1(&op, &[("a.txt", b"hello"), ("b.txt", b"world!")]).await?;
+        let mut fs = Fs::create(op).await?;
+        let manifest = fs.read_metadata().await?;
+        fs.read_manifest(&manifest).await?;
+        assert_eq!(fs.stat("a.txt").unwrap().stored_size(), None);
+        assert_eq!(fs.physical_size().await?, 11);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encryption_same_ids_across_machines() -> Result<()> {
+        let content = Buffer::from(text(10_000));
+        let mut results = vec![];
+        for _ in 0..2 {
+            let op = memory();
+            let mut fs = Fs::builder(op.clone())
+                .chunk_size(4096)
+                .encryption_key([42; 32])
+                .build()
+                .await?;
+            let file = fs.replace_file("a.txt", content.clone()).await?;
+            fs.commit().await?;
+            let stored = op.read(&format!("data/{}", file.chunks()[0])).await?;
+            results.push((file.chunks().to_vec(), stored.to_vec(), op));
+        }
+
+        // Object names are plaintext hashes, while the bytes differ by nonce.
+        assert_eq!(results[0].0, results[1].0);
+        assert_ne!(results[0].1, results[1].1);
+        let plain = content.slice(..64).to_vec();
+        assert!(!results[0].1.windows(64).any(|w| w == plain));
+
+        let op = results[1].2.clone();
+        let mut fs = Fs::builder(op.clone())
+            .encryption_key([42; 32])
+            .build()
+            .await?;
+        let manifest = fs.read_metadata().await?;
+        fs.read_manifest(&manifest).await?;
+        assert_eq!(fs.read("a.txt").await?.to_vec(), content.to_vec());
+        assert!(Fs::builder(op.clone())
+            .encryption_key([43; 32])
+            .build()
+            .await
+            .is_err());
+        assert!(Fs::create(op).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_chunks_are_bound_to_ids() -> Result<()> {
+        let op = memory();
+        let mut fs = Fs::builder(op.clone())
+            .encryption_key([1; 32])
+            .build()
+            .await?;
+        let a = fs.replace_file("a.txt", Buffer::from("aaaa")).await?;
+        let b = fs.replace_file("b.txt", Buffer::from("bbbb")).await?;
+        let (a_path, b_path) = (
+            format!("data/{}", a.chunks()[0]),
+            format!("data/{}", b.chunks()[0]),
+        );
+
+        // An object sealed by the same key can't be swapped in.
+        let stored_a = op.read(&a_path).await?;
+        op.write(&a_path, op.read(&b_path).await?).await?;
+        assert!(fs.read("a.txt").await.is_err());
+
+        // Neither could the header be tampered with.
+        let mut tampered = stored_a.to_vec();
+        tampered[0] = ENCRYPTED_FLAG | Codec::Zstd { level: 3 }.tag();
+        op.write(&a_path, tampered).await?;
+        assert!(fs.read("a.txt").await.is_err());
+
+        op.write(&a_path, stored_a).await?;
+        assert_eq!(fs.read("a.txt").await?.to_vec(), b"aaaa");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_streaming() -> Result<()> {
+        let op = memory();
+        let fs = Fs::builder(op.clone())
+            .chunk_size(4096)
+            .encryption_key([9; 32])
+            .build()
+            .await?;
+
+        // Streamed chunks are encrypted, readable and named by plaintext hashes.
+        let content = Buffer::from(text(10_000));
+        let mut w = fs.new_streaming_writer("a.txt");
+        w.write(content.clone()).await?;
+        let streamed = w.close().await?.file;
+        let mut w = fs.new_file_writer("b.txt");
+        w.write(content.clone()).await?;
+        assert_eq!(streamed.chunks(), w.close().await?.file.chunks());
+        let plain = content.to_vec();
+        for chunk_id in streamed.chunks() {
+            let stored = op.read(&format!("data/{chunk_id}")).await?.to_vec();
+            assert!(!stored.windows(32).any(|w| w == &plain[..32]));
+        }
+
+        let reader = FileReader::new(fs.ctx.clone(), streamed);
+        assert_eq!(reader.read_all().await?.to_vec(), content.to_vec());
+        Ok(())
+    }
+
+    /// Open the file system with files of the latest version loaded.
+    async fn open_latest(op: Operator) -> Result<Fs> {
+        let mut fs = Fs::create(op).await?;
+        let manifest = fs.read_metadata().await?;
+        fs.read_manifest(&manifest).await?;
+        Ok(fs)
+    }
+
+    /// Initialize a file system with one chunked file for every path.
+    async fn init_with_files(op: &Operator, paths: &[&str]) -> Result<()> {
+        let mut fs = Fs::init(op.clone()).await?;
+        for path in paths {
+            fs.replace_file(path, Buffer::from(path.as_bytes().to_vec()))
+                .await?;
+        }
+        fs.commit().await?;
+        Ok(())
+    }
+
+    fn paths(fs: &Fs) -> Vec<&str> {
+        fs.list().map(|file| file.path()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_merge_keeps_their_updates() -> Result<()> {
+        let op = conditional_memory();
+        init_with_files(&op, &["a", "b"]).await?;
+        let mut ours = open_latest(op.clone()).await?;
+        let mut theirs = open_latest(op.clone()).await?;
+
+        theirs.replace_file("a", Buffer::from("theirs")).await?;
+        theirs.commit().await?;
+        ours.replace_file("b", Buffer::from("ours")).await?;
+        assert_eq!(ours.commit().await?, 3);
+
+        let fs = open_latest(op).await?;
+        assert_eq!(fs.read("a").await?.to_vec(), b"theirs");
+        assert_eq!(fs.read("b").await?.to_vec(), b"ours");
+        // Files in memory are reloaded from the merged manifest.
+        assert_eq!(ours.read("a").await?.to_vec(), b"theirs");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_merge_keeps_their_deletes() -> Result<()> {
+        let op = conditional_memory();
+        init_with_files(&op, &["a", "b"]).await?;
+        let mut ours = open_latest(op.clone()).await?;
+        let mut theirs = open_latest(op.clone()).await?;
+
+        theirs.remove_file("a");
+        theirs.commit().await?;
+        ours.replace_file("b", Buffer::from("ours")).await?;
+        ours.commit().await?;
+
+        let fs = open_latest(op).await?;
+        assert_eq!(paths(&fs), vec!["b"]);
+        assert_eq!(fs.read("b").await?.to_vec(), b"ours");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_merge_applies_our_deletes() -> Result<()> {
+        let op = conditional_memory();
+        init_with_files(&op, &["a", "b"]).await?;
+        let mut ours = open_latest(op.clone()).await?;
+        let mut theirs = open_latest(op.clone()).await?;
+
+        theirs.replace_file("c", Buffer::from("theirs")).await?;
+        theirs.commit().await?;
+        ours.remove_file("a");
+        ours.commit().await?;
+
+        let fs = open_latest(op).await?;
+        assert_eq!(paths(&fs), vec!["b", "c"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_merge_conflict() -> Result<()> {
+        let op = conditional_memory();
+        init_with_files(&op, &["a"]).await?;
+        let mut ours = open_latest(op.clone()).await?;
+        let mut theirs = open_latest(op.clone()).await?;
+
+        theirs.replace_file("a", Buffer::from("theirs")).await?;
+        theirs.commit().await?;
+        ours.replace_file("a", Buffer::from("ours")).await?;
+        let err = ours.commit().await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::MergeConflict {
+                path: "a".to_string()
+            })
+        );
+
+        let fs = open_latest(op).await?;
+        assert_eq!(fs.read("a").await?.to_vec(), b"theirs");
+        Ok(())
+    }
+
+    /// Commit a file system of chunked files for every path into the store.
+    async fn commit_paths(op: &Operator, store: &MemoryChunkStore, paths: &[String]) -> Result<()> {
+        let mut fs = Fs::builder(op.clone())
+            .chunk_store(store.clone())
+            .build()
+            .await?;
+        for path in paths {
+            fs.replace_file(path, Buffer::from(path.clone().into_bytes()))
+                .await?;
+        }
+        fs.commit().await?;
+        Ok(())
+    }
+
+    fn dir_paths(dirs: &[&str], n: usize) -> Vec<String> {
+        dirs.iter()
+            .flat_map(|dir| (0..n).map(move |i| format!("{dir}/{i}")))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_segmented_manifest() -> Result<()> {
+        let op = memory();
+        let store = MemoryChunkStore::default();
+        let paths = dir_paths(&["a", "b", "c"], 4);
+        commit_paths(&op, &store, &paths).await?;
+
+        let fs = Fs::builder(op).chunk_store(store).build().await?;
+        let manifest = fs.read_metadata().await?;
+        let content = fs.ctx.read_chunk(&manifest).await?.to_bytes();
+        let content = content
+            .strip_prefix(specs_v2::MANIFEST_INDEX_MAGIC)
+            .unwrap();
+        let (index, _): (specs_v2::ManifestIndex, _) =
+            bincode::decode_from_slice(content, bincode::config::standard())?;
+        let ranges: Vec<_> = index
+            .segments
+            .iter()
+            .map(|segment| (segment.first.as_str(), segment.last.as_str()))
+            .collect();
+        assert_eq!(ranges, vec![("a/0", "a/3"), ("b/0", "b/3"), ("c/0", "c/3")]);
+        let files = fs.read_manifest_into(&manifest).await?;
+        assert_eq!(files.keys().cloned().collect::<Vec<_>>(), paths);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_dir_stream_loads_covering_segments() -> Result<()> {
+        let op = memory();
+        let store = MemoryChunkStore::default();
+        commit_paths(&op, &store, &dir_paths(&["a", "b", "c"], 4)).await?;
+
+        let fs = Fs::builder(op).chunk_store(store.clone()).build().await?;
+        let gets = store.gets();
+        let files: Vec<File> = fs.list_dir_stream("b/").try_collect().await?;
+        let paths: Vec<&str> = files.iter().map(|file| file.path()).collect();
+        assert_eq!(paths, vec!["b/0", "b/1", "b/2", "b/3"]);
+        // The index and the only segment of `b/`.
+        assert_eq!(store.gets() - gets, 2);
+
+        let gets = store.gets();
+        let files: Vec<File> = fs.list_dir_stream("d/").try_collect().await?;
+        assert!(files.is_empty());
+        assert_eq!(store.gets() - gets, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_dir_stream_across_segments() -> Result<()> {
+        let op = memory();
+        let store = MemoryChunkStore::default();
+        commit_paths(&op, &store, &dir_paths(&["a", "b"], 6)).await?;
+
+        let fs = Fs::builder(op).chunk_store(store).build().await?;
+        let files: Vec<File> = fs.list_dir_stream("b/").try_collect().await?;
+        let paths: Vec<&str> = files.iter().map(|file| file.path()).collect();
+        assert_eq!(paths, vec!["b/0", "b/1", "b/2", "b/3", "b/4", "b/5"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pins_survive_gc() -> Result<()> {
+        let mut fs = Fs::create(memory()).await?;
+        let mut writer = fs.new_file_writer("a").with_pin(true);
+        writer.write(Buffer::from(text(4096))).await?;
+        let result = writer.close().await?;
+        let pin = result.pin.unwrap();
+        fs.insert_file(result.file);
+        fs.commit().await?;
+
+        fs.remove_file("a");
+        fs.commit().await?;
+        assert_eq!(fs.gc().await?.deleted_chunks, 1);
+        assert_eq!(fs.read_by_id(&pin).await?.to_vec(), text(4096));
+
+        fs.unpin(&pin).await?;
+        assert_eq!(fs.gc().await?.deleted_chunks, 2);
+        assert!(fs.read_by_id(&pin).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_audit_storage() -> Result<()> {
+        let op = memory();
+        let mut fs = Fs::builder(op.clone()).retain_versions(1).build().await?;
+        let old = fs.replace_file("a", Buffer::from("old")).await?;
+        fs.commit().await?;
+        let new = fs.replace_file("a", Buffer::from("new")).await?;
+        fs.commit().await?;
+        let orphan = fs.ctx.write_chunk(Buffer::from("orphan")).await?;
+        let mismatched = Blake3Hasher.chunk_id(&Buffer::from("expected"));
+        fs.ctx
+            .store
+            .put(&mismatched, Buffer::from(vec![0, b'x']))
+            .await?;
+        op.write("data/not-a-chunk", "foreign").await?;
+
+        let audit = fs.audit_storage(true).await?;
+        assert!(audit.referenced.contains(&old.chunks()[0]));
+        assert!(audit.referenced.contains(&new.chunks()[0]));
+        assert_eq!(audit.unreferenced, vec![orphan]);
+        assert_eq!(audit.foreign, vec!["not-a-chunk".to_string()]);
+        assert_eq!(audit.mismatched, vec![mismatched]);
+
+        // Unreferenced chunks are exactly the ones deleted by gc.
+        let report = fs.gc().await?;
+        assert_eq!(report.deleted_chunks, 2);
+        assert_eq!(report.live_chunks, audit.referenced.len());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_check_commit_ready() -> Result<()> {
+        let op = conditional_memory();
+        init_with_files(&op, &["a"]).await?;
+        let ours = open_latest(op.clone()).await?;
+        let mut theirs = open_latest(op).await?;
+        assert!(ours.check_commit_ready().await?);
+
+        theirs.replace_file("b", Buffer::from("theirs")).await?;
+        theirs.commit().await?;
+        assert!(!ours.check_commit_ready().await?);
+        assert!(theirs.check_commit_ready().await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_replace_file_keeps_entry_on_failure() -> Result<()> {
+        let store = MemoryChunkStore::default();
+        let mut fs = Fs::builder(memory())
+            .chunk_store(store.clone())
+            .chunk_size(1024)
+            .build()
+            .await?;
+        let old = fs.replace_file("a", Buffer::from(noise(4096))).await?;
+
+        store.fail_puts(true);
+        let mut content = noise(4096);
+        content.extend_from_slice(&text(4096));
+        assert!(fs.replace_file("a", Buffer::from(content)).await.is_err());
+        assert_eq!(fs.stat("a"), Some(&old));
+
+        store.fail_puts(false);
+        assert_eq!(fs.read("a").await?.to_vec(), noise(4096));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_touch() -> Result<()> {
+        let op = memory();
+        let mut fs = Fs::create(op.clone()).await?;
+        let file = fs.replace_file("a", Buffer::from(text(4096))).await?;
+        fs.commit().await?;
+        let objects = op.list_with("data/").await?.len();
+
+        let when = DateTime::from_timestamp(1_800_000_000, 0).unwrap();
+        fs.touch("a", Some(when))?;
+        fs.commit().await?;
+        assert!(fs.touch("b", None).is_err());
+
+        let fs = open_latest(op.clone()).await?;
+        let touched = fs.stat("a").unwrap();
+        assert_eq!(touched.last_modified(), when);
+        assert_eq!(touched.chunks(), file.chunks());
+        assert_eq!(touched.size(), file.size());
+        // Only the new manifest is written.
+        assert_eq!(op.list_with("data/").await?.len(), objects + 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_merkle_root() -> Result<()> {
+        let op = memory();
+        let mut fs = Fs::create(op.clone()).await?;
+        let empty = fs.merkle_root();
+        for path in ["a", "b", "c"] {
+            fs.replace_file(path, Buffer::from(path)).await?;
+        }
+        let root = fs.merkle_root();
+        assert_ne!(root, empty);
+        fs.commit().await?;
+        assert_eq!(open_latest(op).await?.merkle_root(), root);
+
+        fs.replace_file("b", Buffer::from("changed")).await?;
+        let changed = fs.merkle_root();
+        assert_ne!(changed, root);
+        fs.remove_file("c");
+        assert_ne!(fs.merkle_root(), changed);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_chunk_with_id() -> Result<()> {
+        let op = memory();
+        let fs = Fs::builder(op.clone())
+            .verify_given_ids(true)
+            .build()
+            .await?;
+        let buf = Buffer::from("hello");
+        let id = Blake3Hasher.chunk_id(&buf);
+        assert_eq!(fs.ctx.write_chunk_with_id(buf.clone(), &id).await?, id);
+        assert!(op.exists(&format!("data/{id}")).await?);
+        assert_eq!(fs.ctx.read_chunk(&id).await?.to_vec(), b"hello");
+
+        let wrong = Blake3Hasher.chunk_id(&Buffer::from("world"));
+        assert!(fs
+            .ctx
+            .write_chunk_with_id(buf.clone(), &wrong)
+            .await
+            .is_err());
+        assert!(!op.exists(&format!("data/{wrong}")).await?);
+
+        // Malformed ids are rejected even if given ids are trusted.
+        let fs = Fs::builder(op.clone())
+            .verify_given_ids(false)
+            .build()
+            .await?;
+        let res = fs.ctx.write_chunk_with_id(buf.clone(), "../metadata").await;
+        assert!(res.is_err());
+        assert!(!op.exists("metadata").await?);
+        assert_eq!(fs.ctx.write_chunk_with_id(buf, &wrong).await?, wrong);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_foreign_object_at_chunk_path() -> Result<()> {
+        let op = memory();
+        let buf = Buffer::from("hello");
+        let id = Blake3Hasher.chunk_id(&buf);
+        // A foreign object of the same size as the stored chunk.
+        op.write(&format!("data/{id}"), b"\0world".to_vec()).await?;
+
+        let fs = Fs::builder(op.clone())
+            .verify_existing_content(true)
+            .build()
+            .await?;
+        let err = fs.ctx.write_chunk(buf.clone()).await.unwrap_err();
+        assert!(err.to_string().contains("foreign content"), "{err}");
+
+        // The foreign object is trusted without the content check.
+        let fs = Fs::create(op.clone()).await?;
+        assert_eq!(fs.ctx.write_chunk(buf).await?, id);
+        assert_eq!(op.read(&format!("data/{id}")).await?.to_vec(), b"\0world");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_export_and_replicate_concurrently() -> Result<()> {
+        let mut fs = Fs::builder(memory()).chunk_size(1024).build().await?;
+        let contents: Vec<(String, Vec<u8>)> = (0..20)
+            .map(|i| (format!("dir/{i}"), text(1000 + i * 700)))
+            .collect();
+        for (path, content) in &contents {
+            fs.replace_file(path, Buffer::from(content.clone())).await?;
+        }
+
+        for concurrency in [1, 8] {
+            let dest = memory();
+            fs.export_to(&dest, concurrency).await?;
+            for (path, content) in &contents {
+                assert_eq!(&dest.read(path).await?.to_vec(), content);
+            }
+        }
+
+        let op = memory();
+        let mut dest = Fs::builder(op.clone())
+            .compression(Codec::Zstd { level: 3 })
+            .build()
+            .await?;
+        fs.replicate_to(&mut dest, 4).await?;
+        dest.commit().await?;
+        let dest = open_latest(op).await?;
+        for (path, content) in &contents {
+            assert_eq!(&dest.read(path).await?.to_vec(), content);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hotspots() -> Result<()> {
+        let mut fs = Fs::builder(memory()).chunk_size(1024).build().await?;
+        let blocks = noise(4 * 1024);
+        let block = |i: usize| &blocks[i * 1024..(i + 1) * 1024];
+        for (path, ids) in [
+            ("a", &[0, 1, 2][..]),
+            ("b", &[0, 1]),
+            ("c", &[0]),
+            ("d", &[3]),
+        ] {
+            let content: Vec<u8> = ids.iter().flat_map(|&i| block(i).to_vec()).collect();
+            fs.replace_file(path, Buffer::from(content)).await?;
+        }
+        let id = |i: usize| Blake3Hasher.chunk_id(&Buffer::from(block(i).to_vec()));
+
+        let hotspots = fs.hotspots(2);
+        assert_eq!(
+            hotspots.largest_files,
+            vec![("a".to_string(), 3072), ("b".to_string(), 2048)]
+        );
+        assert_eq!(hotspots.hot_chunks, vec![(id(0), 3), (id(1), 2)]);
+        assert_eq!(fs.hotspots(10).hot_chunks.len(), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_manifest_into_leaves_files_untouched() -> Result<()> {
+        let op = conditional_memory();
+        init_with_files(&op, &["a", "b"]).await?;
+        let manifest = Fs::create(op.clone()).await?.read_metadata().await?;
+
+        let mut fs = Fs::create(op).await?;
+        let local = fs.replace_file("c", Buffer::from("local")).await?;
+        let files = fs.read_manifest_into(&manifest).await?;
+        assert_eq!(files.keys().collect::<Vec<_>>(), vec!["a", "b"]);
+        assert_eq!(paths(&fs), vec!["c"]);
+        assert_eq!(fs.stat("c"), Some(&local));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify_all_reports_each_level() -> Result<()> {
+        let op = memory();
+        let mut fs = Fs::builder(op.clone()).chunk_size(1024).build().await?;
+        let file = fs.replace_file("a", Buffer::from(noise(2048))).await?;
+        fs.commit().await?;
+        assert_eq!(fs.verify_all(true).await?, VerifyReport::default());
+
+        // A missing chunk is reported under the file using it.
+        let chunk = file.chunks()[1].clone();
+        let content = op.read(&format!("data/{chunk}")).await?;
+        op.delete(&format!("data/{chunk}")).await?;
+        let report = fs.verify_all(false).await?;
+        assert_eq!(report.missing_chunks["a"], vec![chunk.clone()]);
+        assert!(report.metadata_error.is_none() && report.manifest_error.is_none());
+        op.write(&format!("data/{chunk}"), content).await?;
+        assert!(fs.verify_all(true).await?.is_ok());
+
+        // A missing manifest stops the check before chunks.
+        let manifest = fs.read_metadata().await?;
+        let content = op.read(&format!("data/{manifest}")).await?;
+        op.delete(&format!("data/{manifest}")).await?;
+        let report = fs.verify_all(true).await?;
+        assert!(report.metadata_error.is_none());
+        assert!(report.manifest_error.unwrap().contains(&manifest));
+        assert!(report.missing_chunks.is_empty());
+        op.write(&format!("data/{manifest}"), content).await?;
+
+        // Metadata that doesn't decode stops the check right away.
+        op.write("metadata", "garbage").await?;
+        let report = fs.verify_all(true).await?;
+        assert!(report.metadata_error.is_some());
+        assert!(report.manifest_error.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_files_containing_chunk() -> Result<()> {
+        let mut fs = Fs::builder(memory()).chunk_size(1024).build().await?;
+        let shared = noise(1024);
+        let a = fs
+            .replace_file("a", Buffer::from([&shared[..], &text(1024)].concat()))
+            .await?;
+        fs.replace_file("b", Buffer::from([&text(2048)[1024..], &shared].concat()))
+            .await?;
+        fs.replace_file("c", Buffer::from(text(4096))).await?;
+
+        let files = fs.files_containing_chunk(&a.chunks()[0]);
+        let paths: Vec<_> = files.iter().map(|file| file.path()).collect();
+        assert_eq!(paths, vec!["a", "b"]);
+        assert!(fs.files_containing_chunk("missing").is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_verified() -> Result<()> {
+        let mut fs = Fs::builder(memory()).chunk_size(1024).build().await?;
+        let content = Buffer::from(noise(3000));
+        fs.replace_file("a", content.clone()).await?;
+
+        let hash = Blake3Hasher.chunk_id(&content);
+        assert_eq!(
+            fs.read_verified("a", &hash).await?.to_vec(),
+            content.to_vec()
+        );
+
+        let other = Blake3Hasher.chunk_id(&content.slice(..2999));
+        let err = fs.read_verified("a", &other).await.unwrap_err();
+        assert!(err.to_string().contains("content hash mismatch"), "{err}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_export_file() -> Result<()> {
+        for concurrency in [1, 8] {
+            let mut fs = Fs::builder(memory())
+                .chunk_size(1024)
+                .read_concurrency(concurrency)
+                .build()
+                .await?;
+            let content = Buffer::from(noise(5000));
+            fs.replace_file("a", content.clone()).await?;
+            fs.replace_file("b", Buffer::from(text(3000))).await?;
+
+            let dest = memory();
+            fs.export_file("a", &dest, "out/a.bin").await?;
+            assert_eq!(dest.read("out/a.bin").await?.to_vec(), content.to_vec());
+            let entries = dest.list_with("").recursive(true).await?;
+            let names: Vec<_> = entries.iter().map(|e| e.path()).collect();
+            assert_eq!(names, vec!["out/a.bin"]);
+
+            assert!(fs.export_file("missing", &dest, "x").await.is_err());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_init() -> Result<()> {
+        for op in [memory(), conditional_memory()] {
+            let mut fs = Fs::init(op.clone()).await?;
+            assert!(fs.is_empty());
+            assert!(fs.check_commit_ready().await?);
+            fs.replace_file("a", Buffer::from("a")).await?;
+            assert_eq!(fs.commit().await?, 1);
+            assert!(fs.check_commit_ready().await?);
+
+            let err = Fs::init(op.clone()).await.err().unwrap();
+            assert!(matches!(
+                err.downcast_ref::<Error>(),
+                Some(Error::AlreadyExists)
+            ));
+            let fs = open_latest(op).await?;
+            assert_eq!(fs.read("a").await?.to_vec(), b"a");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_format() -> Result<()> {
+        let op = memory();
+        let mut fs = Fs::create(op.clone()).await?;
+        let mut writer = fs.new_file_writer("a.parquet").with_format("parquet");
+        writer.write(Buffer::from(noise(100))).await?;
+        fs.insert_file(writer.close().await?.file);
+        fs.replace_file("b", Buffer::from("b")).await?;
+        fs.commit().await?;
+
+        let fs = open_latest(op).await?;
+        assert_eq!(fs.stat("a.parquet").unwrap().format(), Some("parquet"));
+        assert_eq!(fs.stat("b").unwrap().format(), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_seal_retained_version() -> Result<()> {
+        let op = memory();
+        let mut fs = Fs::builder(op.clone())
+            .chunk_size(1024)
+            .retain_versions(1)
+            .build()
+            .await?;
+        let v1 = Buffer::from(noise(2048));
+        let sealed = fs.replace_file("a", v1.clone()).await?;
+        assert_eq!(fs.commit().await?, 1);
+        fs.replace_file("a", Buffer::from(text(2048))).await?;
+        assert_eq!(fs.commit().await?, 2);
+        fs.seal(1).await?;
+        assert!(fs.is_sealed(1).await? && !fs.is_sealed(2).await?);
+        assert!(fs.seal(5).await.is_err());
+
+        // Sealing an older version doesn't block commits on the current one.
+        fs.replace_file("a", Buffer::from(text(3000))).await?;
+        assert_eq!(fs.commit().await?, 3);
+        fs.gc().await?;
+        for chunk in sealed.chunks() {
+            assert!(op.exists(&format!("data/{chunk}")).await?);
+        }
+
+        // Version 1 has dropped out of history but is still loadable.
+        let mut old = Fs::create(op.clone()).await?;
+        old.load_version(1).await?;
+        assert_eq!(old.read("a").await?.to_vec(), v1.to_vec());
+        assert!(old.load_version(2).await.is_ok());
+
+        // Sealing the current version freezes the file system.
+        fs.seal(3).await?;
+        fs.replace_file("b", Buffer::from("b")).await?;
+        let err = fs.commit().await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::Sealed { version: 3 })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wrong_file_size_in_manifest() -> Result<()> {
+        let op = memory();
+        let mut fs = Fs::builder(op.clone()).chunk_size(1024).build().await?;
+        let file = fs.replace_file("a", Buffer::from(noise(3000))).await?;
+        let mut entry: specs_v2::File = file.into();
+        entry.size = 4096;
+        let manifest = fs.write_segment(vec![entry]).await?;
+
+        let err = fs.read_manifest(&manifest).await.unwrap_err();
+        assert!(err.to_string().contains("chunks sum to 3000"), "{err}");
+
+        let mut fs = Fs::builder(op).repair_sizes(true).build().await?;
+        fs.read_manifest(&manifest).await?;
+        assert_eq!(fs.stat("a").unwrap().size(), 3000);
+        assert_eq!(fs.read("a").await?.len(), 3000);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retry_corrupted_uploads() -> Result<()> {
+        let store = MemoryChunkStore::default();
+        let mut fs = Fs::builder(memory())
+            .chunk_store(store.clone())
+            .chunk_size(1024)
+            .verify_uploads(true)
+            .build()
+            .await?;
+        let content = Buffer::from(noise(2048));
+        store.corrupt_puts(UPLOAD_RETRIES);
+        fs.replace_file("a", content.clone()).await?;
+        assert_eq!(fs.read("a").await?.to_vec(), content.to_vec());
+        assert!(fs.verify(VerifyOptions::new().deep(true)).await?.is_ok());
+
+        // Streamed chunks are uploaded again as well.
+        let content = Buffer::from(noise(4096));
+        store.corrupt_puts(1);
+        let mut writer = fs.new_streaming_writer("c");
+        writer.write(content.clone()).await?;
+        fs.insert_file(writer.close().await?.file);
+        assert_eq!(fs.read("c").await?.to_vec(), content.to_vec());
+
+        store.corrupt_puts(UPLOAD_RETRIES + 1);
+        let err = fs
+            .replace_file("b", Buffer::from(text(1024)))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("still corrupted"), "{err}");
+        Ok(())
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn test_in_memory() -> Result<()> {
+        let mut fs = Fs::in_memory();
+        fs.replace_file("a", Buffer::from(text(10_000))).await?;
+        assert_eq!(fs.commit().await?, 1);
+
+        let fs = open_latest(fs.operator().clone()).await?;
+        assert_eq!(fs.read("a").await?.to_vec(), text(10_000));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_manifest_independent_of_insertion_order() -> Result<()> {
+        let op = memory();
+        let mut fs = Fs::builder(op.clone()).chunk_size(1024).build().await?;
+        let mut files = vec![];
+        for (idx, path) in ["b", "a/c", "c", "a", "a/b/d"].iter().enumerate() {
+            files.push(
+                fs.replace_file(path, Buffer::from(noise(500 * idx)))
+                    .await?,
+            );
+        }
+        let expected = fs.write_manifest().await?;
+
+        for order in [[4, 3, 2, 1, 0], [2, 0, 4, 1, 3]] {
+            let mut other = Fs::create(op.clone()).await?;
+            for idx in order {
+                other.insert_file(files[idx].clone());
+            }
+            assert_eq!(other.write_manifest().await?, expected);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_apply_patch() -> Result<()> {
+        let op = memory();
+        let mut fs = Fs::builder(op.clone()).chunk_size(1024).build().await?;
+        fs.replace_file("x", Buffer::from("x")).await?;
+        fs.replace_file("y", Buffer::from("y")).await?;
+
+        let mut adds = vec![];
+        for (path, content) in [("a", noise(3000)), ("b", text(1500))] {
+            let mut writer = fs.new_file_writer(path);
+            writer.write(Buffer::from(content)).await?;
+            let file = writer.close().await?.file;
+            adds.push(PatchFile {
+                path: path.to_string(),
+                chunks: file.chunks().to_vec(),
+                chunk_sizes: file.chunk_sizes().to_vec(),
+                size: file.size(),
+            });
+        }
+        let patch = ManifestPatch {
+            adds,
+            removes: vec!["y".to_string()],
+            renames: vec![],
+            verify_chunks: true,
+        };
+        let patch = ManifestPatch::from_bytes(&patch.to_bytes()?)?;
+        fs.apply_patch(patch).await?;
+        fs.commit().await?;
+
+        let mut fs = open_latest(op).await?;
+        assert_eq!(paths(&fs), vec!["a", "b", "x"]);
+        assert_eq!(fs.read("a").await?.to_vec(), noise(3000));
+        assert_eq!(fs.read("b").await?.to_vec(), text(1500));
+
+        // Patches referencing missing chunks are rejected as a whole.
+        let patch = ManifestPatch {
+            adds: vec![PatchFile {
+                path: "c".to_string(),
+                chunks: vec!["missing".to_string()],
+                chunk_sizes: vec![1],
+                size: 1,
+            }],
+            removes: vec!["x".to_string()],
+            renames: vec![],
+            verify_chunks: true,
+        };
+        assert!(fs.apply_patch(patch).await.is_err());
+        assert_eq!(paths(&fs), vec!["a", "b", "x"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_publish_after_conflict_without_reupload() -> Result<()> {
+        let op = conditional_memory();
+        Fs::init(op.clone()).await?;
+        let store = MemoryChunkStore::default();
+        let build = || {
+            Fs::builder(op.clone())
+                .chunk_store(store.clone())
+                .chunk_size(1024)
+                .build()
+        };
+        let mut ours = build().await?;
+        ours.replace_file("a", Buffer::from(noise(3000))).await?;
+        ours.commit().await?;
+        let mut theirs = build().await?;
+        let manifest = theirs.read_metadata().await?;
+        theirs.read_manifest(&manifest).await?;
+
+        ours.replace_file("b", Buffer::from(text(5000))).await?;
+        let staged = ours.stage().await?;
+        assert_eq!(ours.stage().await?, staged);
+        theirs.replace_file("c", Buffer::from("c")).await?;
+        theirs.commit().await?;
+
+        // Only the merged manifest is uploaded by the publish.
+        let puts = store.puts();
+        ours.publish(&staged).await?;
+        assert_eq!(store.puts() - puts, 1);
+
+        let mut fs = build().await?;
+        let manifest = fs.read_metadata().await?;
+        fs.read_manifest(&manifest).await?;
+        assert_eq!(paths(&fs), vec!["a", "b", "c"]);
+        assert_eq!(fs.read("b").await?.to_vec(), text(5000));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_skip_existing_chunks() -> Result<()> {
+        let store = MemoryChunkStore::default();
+        let mut fs = Fs::builder(memory())
+            .chunk_store(store.clone())
+            .chunk_size(1024)
+            .build()
+            .await?;
+        let content = Buffer::from(noise(3072));
+        let file = fs.replace_file("a", content.clone()).await?;
+        assert_eq!(store.puts(), 3);
+
+        // Existing chunks of the expected size are skipped.
+        fs.replace_file("b", content.clone()).await?;
+        assert_eq!(store.puts(), 3);
+
+        // Existing chunks of another size are overwritten.
+        store.put(&file.chunks()[1], Buffer::from("short")).await?;
+        let puts = store.puts();
+        fs.replace_file("c", content.clone()).await?;
+        assert_eq!(store.puts() - puts, 1);
+        assert_eq!(fs.read("a").await?.to_vec(), content.to_vec());
+
+        // The existence check is skipped entirely if disabled.
+        let mut fs = Fs::builder(memory())
+            .chunk_store(store.clone())
+            .chunk_size(1024)
+            .verify_existing(false)
+            .build()
+            .await?;
+        let puts = store.puts();
+        fs.replace_file("a", content).await?;
+        assert_eq!(store.puts() - puts, 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chunk_size_recorded() -> Result<()> {
+        let op = memory();
+        let mut fs = Fs::builder(op.clone()).chunk_size(1024).build().await?;
+        let a = fs.replace_file("a", Buffer::from(noise(3000))).await?;
+        assert_eq!(a.chunk_sizes(), [1024, 1024, 952]);
+        fs.commit().await?;
+
+        // Later opens adopt the recorded chunk size.
+        let mut fs = open_latest(op.clone()).await?;
+        assert_eq!(fs.info().chunk_size, 1024);
+        let b = fs.replace_file("b", Buffer::from(noise(3000))).await?;
+        assert_eq!(b.chunks(), a.chunks());
+
+        // An explicit chunk size overrides the recorded one, files written
+        // by the old one are still readable.
+        let mut fs = Fs::builder(op.clone()).chunk_size(2048).build().await?;
+        let manifest = fs.read_metadata().await?;
+        fs.read_manifest(&manifest).await?;
+        assert_eq!(fs.info().chunk_size, 2048);
+        let c = fs.replace_file("c", Buffer::from(noise(3000))).await?;
+        assert_eq!(c.chunk_sizes(), [2048, 952]);
+        fs.commit().await?;
+
+        let fs = open_latest(op).await?;
+        assert_eq!(fs.info().chunk_size, 2048);
+        assert_eq!(fs.read("a").await?.to_vec(), noise(3000));
+        assert_eq!(fs.read("c").await?.to_vec(), noise(3000));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_merge_manifest() -> Result<()> {
+        let at = |secs| DateTime::from_timestamp(secs, 0);
+        let op = memory();
+        let mut remote = Fs::create(op.clone()).await?;
+        for path in ["remote_newer", "local_newer", "remote_only"] {
+            remote.replace_file(path, Buffer::from("remote")).await?;
+        }
+        remote.touch("remote_newer", at(2_000))?;
+        remote.touch("local_newer", at(1_000))?;
+        remote.commit().await?;
+        let manifest = remote.read_metadata().await?;
+
+        let mut local = Fs::create(op).await?;
+        for path in ["remote_newer", "local_newer", "local_only"] {
+            local.replace_file(path, Buffer::from("local")).await?;
+        }
+        local.touch("remote_newer", at(1_500))?;
+        local.touch("local_newer", at(1_500))?;
+        local.merge_manifest(&manifest).await?;
+
+        assert_eq!(
+            paths(&local),
+            vec!["local_newer", "local_only", "remote_newer", "remote_only"]
+        );
+        for (path, expected) in [
+            ("remote_newer", "remote"),
+            ("local_newer", "local"),
+            ("remote_only", "remote"),
+            ("local_only", "local"),
+        ] {
+            assert_eq!(local.read(path).await?.to_vec(), expected.as_bytes());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_gc() -> Result<()> {
+        let op = memory();
+        let mut fs = Fs::builder(op.clone()).chunk_size(1024).build().await?;
+        let old = fs.replace_file("a", Buffer::from(noise(3072))).await?;
+        let removed = fs.replace_file("b", Buffer::from(text(1024))).await?;
+        fs.commit().await?;
+        let old_manifest = fs.read_metadata().await?;
+
+        let mut content = noise(2048);
+        content.extend(text(3072)[2048..].iter());
+        fs.replace_file("a", Buffer::from(content.clone())).await?;
+        fs.remove_file("b");
+        fs.commit().await?;
+        let manifest = fs.read_metadata().await?;
+
+        // Retained manifests keep their chunks.
+        let report = fs.gc_retaining(std::slice::from_ref(&old_manifest)).await?;
+        assert_eq!(report.deleted_chunks, 0);
+
+        let report = fs.gc().await?;
+        let mut dead = vec![old.chunks()[2].clone(), removed.chunks()[0].clone()];
+        dead.push(old_manifest);
+        assert_eq!(report.deleted_chunks, dead.len());
+        assert!(report.reclaimed_bytes > 2 * 1024);
+        for chunk in &dead {
+            assert!(!op.exists(&format!("data/{chunk}")).await?);
+        }
+        assert!(op.exists(&format!("data/{manifest}")).await?);
+        assert_eq!(
+            fs.gc().await?,
+            GcReport {
+                live_chunks: report.live_chunks,
+                ..Default::default()
+            }
+        );
+
+        let fs = open_latest(op).await?;
+        assert_eq!(fs.read("a").await?.to_vec(), content);
+        assert!(fs.verify_all(true).await?.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_remove_file() -> Result<()> {
+        let op = memory();
+        let mut fs = Fs::create(op.clone()).await?;
+        assert!(fs.is_empty());
+        let a = fs.replace_file("a", Buffer::from("a")).await?;
+        fs.replace_file("b", Buffer::from("b")).await?;
+        fs.commit().await?;
+        assert_eq!(fs.len(), 2);
+
+        assert_eq!(fs.remove_file("a"), Some(a));
+        assert_eq!(fs.remove_file("a"), None);
+        assert!(!fs.contains("a") && fs.contains("b"));
+        assert_eq!(fs.len(), 1);
+        fs.commit().await?;
+
+        let fs = open_latest(op).await?;
+        assert_eq!(paths(&fs), vec!["b"]);
+        assert!(fs.read("a").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_range() -> Result<()> {
+        let store = MemoryChunkStore::default();
+        let mut fs = Fs::builder(memory())
+            .chunk_store(store.clone())
+            .chunk_size(1024)
+            .build()
+            .await?;
+        let content = noise(5000);
+        fs.replace_file("a", Buffer::from(content.clone())).await?;
+
+        for (range, chunks) in [
+            (0..1024, 1),
+            (1000..1100, 2),
+            (1024..2048, 1),
+            (2100..4500, 3),
+            (4990..5000, 1),
+            (3000..3000, 0),
+        ] {
+            let gets = store.gets();
+            let buf = fs.read_range("a", range.clone()).await?;
+            assert_eq!(store.gets() - gets, chunks, "{range:?}");
+            let range = range.start as usize..range.end as usize;
+            assert_eq!(buf.to_vec(), content[range]);
+        }
+
+        let err = fs.read_range("a", 4000..5001).await.unwrap_err();
+        assert!(err.to_string().contains("out of file a"), "{err}");
+        assert!(fs.read_range("missing", 0..1).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hash_large_chunks_off_executor() -> Result<()> {
+        let fs = Fs::builder(memory())
+            .chunk_size(2 * BLOCKING_HASH_SIZE)
+            .build()
+            .await?;
+        let content = noise(BLOCKING_HASH_SIZE + 100);
+        let contiguous = Buffer::from(content.clone());
+        let segmented: Buffer = content.chunks(4096).map(Bytes::copy_from_slice).collect();
+        let expected = fs.ctx.hasher().chunk_id(&contiguous);
+        assert_eq!(fs.ctx.hasher().chunk_id(&segmented), expected);
+        assert_eq!(fs.ctx.chunk_id_nonblocking(segmented).await?, expected);
+
+        let small = Buffer::from(content[..100].to_vec());
+        let small_id = fs.ctx.hasher().chunk_id(&small);
+        assert_eq!(fs.ctx.chunk_id_nonblocking(small).await?, small_id);
+
+        let mut writer = fs.new_file_writer("a");
+        writer.write(contiguous).await?;
+        let file = writer.close().await?.file;
+        assert_eq!(file.chunks(), std::slice::from_ref(&expected));
+
+        // Outside a tokio runtime, large chunks are hashed in place.
+        let ctx = fs.ctx.clone();
+        let id = std::thread::spawn(move || {
+            futures::executor::block_on(ctx.chunk_id_nonblocking(Buffer::from(content)))
+        })
+        .join()
+        .unwrap()?;
+        assert_eq!(id, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_version_history() -> Result<()> {
+        let op = memory();
+        let mut fs = Fs::builder(op.clone()).retain_versions(1).build().await?;
+        let mut files = vec![];
+        for content in ["one", "two", "three"] {
+            files.push(fs.replace_file("a", Buffer::from(content)).await?);
+            fs.commit().await?;
+        }
+
+        let mut old = open_latest(op.clone()).await?;
+        old.load_version(2).await?;
+        assert_eq!(old.read("a").await?.to_vec(), b"two");
+        old.load_as_of(Utc::now()).await?;
+        assert_eq!(old.read("a").await?.to_vec(), b"three");
+        let err = old.load_version(1).await.unwrap_err();
+        assert!(err.to_string().contains("not retained"), "{err}");
+        assert!(old.load_as_of(DateTime::UNIX_EPOCH).await.is_err());
+
+        // Only the chunks of versions dropped from history are collected.
+        let report = open_latest(op.clone()).await?.gc().await?;
+        assert!(report.deleted_chunks > 0);
+        assert!(fs.ctx.read_chunk(&files[0].chunks()[0]).await.is_err());
+        old.load_version(2).await?;
+        assert_eq!(old.read("a").await?.to_vec(), b"two");
+        old.load_version(3).await?;
+        assert_eq!(old.read("a").await?.to_vec(), b"three");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify_shallow_and_deep() -> Result<()> {
+        let op = memory();
+        let mut fs = Fs::builder(op.clone()).chunk_size(1024).build().await?;
+        let shared = noise(1024);
+        let a = fs
+            .replace_file("a", Buffer::from([&shared[..], &text(1024)].concat()))
+            .await?;
+        fs.replace_file("b", Buffer::from(shared)).await?;
+        let opts = VerifyOptions::new().concurrency(2);
+        assert!(fs.verify(opts.deep(true)).await?.is_ok());
+
+        // Bit-rot is only detected by deep verification.
+        let rotten = a.chunks()[0].clone();
+        op.write(&format!("data/{rotten}"), vec![0, b'x']).await?;
+        assert!(fs.verify(opts).await?.is_ok());
+        let report = fs.verify(opts.deep(true)).await?;
+        assert_eq!(report.mismatched_chunks["a"], vec![rotten.clone()]);
+        assert_eq!(report.mismatched_chunks["b"], vec![rotten]);
+        assert!(report.missing_chunks.is_empty());
+
+        let missing = a.chunks()[1].clone();
+        op.delete(&format!("data/{missing}")).await?;
+        for deep in [false, true] {
+            let report = fs.verify(opts.deep(deep)).await?;
+            assert_eq!(report.missing_chunks.len(), 1);
+            assert_eq!(report.missing_chunks["a"], vec![missing.clone()]);
+        }
+        Ok(())
+    }
+
+    /// A hasher with shorter hex ids of blake3.
+    struct ShortHasher;
+
+    struct ShortState(blake3::Hasher);
+
+    impl Hasher for ShortHasher {
+        fn name(&self) -> &str {
+            "blake3-short"
+        }
+
+        fn id_encoding(&self) -> &str {
+            "hex"
+        }
+
+        fn start(&self) -> Box<dyn HashState> {
+            Box::new(ShortState(blake3::Hasher::new()))
+        }
+    }
+
+    impl HashState for ShortState {
+        fn update(&mut self, data: &[u8]) {
+            self.0.update(data);
+        }
+
+        fn finish(self: Box<Self>) -> String {
+            self.0.finalize().to_hex()[..16].to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hasher_recorded_in_metadata() -> Result<()> {
+        let op = memory();
+        let mut fs = Fs::builder(op.clone()).hasher(ShortHasher).build().await?;
+        let file = fs.replace_file("a", Buffer::from("hello")).await?;
+        fs.commit().await?;
+        assert_eq!(
+            file.chunks(),
+            [ShortHasher.chunk_id(&Buffer::from("hello"))]
+        );
+        assert!(op.exists(&format!("data/{}", file.chunks()[0])).await?);
+
+        let mut fs = Fs::builder(op.clone()).hasher(ShortHasher).build().await?;
+        let manifest = fs.read_metadata().await?;
+        fs.read_manifest(&manifest).await?;
+        assert_eq!(fs.read("a").await?.to_vec(), b"hello");
+
+        // Mixing hashers within a file system is rejected.
+        let err = Fs::create(op).await.err().unwrap();
+        assert!(
+            err.to_string()
+                .contains("uses hasher blake3-short, but blake3 is configured"),
+            "{err}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_and_chunk_aligned_files() -> Result<()> {
+        let op = memory();
+        let store = MemoryChunkStore::default();
+        let mut fs = Fs::builder(op.clone())
+            .chunk_store(store.clone())
+            .chunk_size(1024)
+            .build()
+            .await?;
+        let empty = fs.new_file_writer("empty").close().await?.file;
+        assert!(empty.chunks().is_empty() && empty.chunk_sizes().is_empty());
+        assert_eq!(empty.size(), 0);
+        fs.insert_file(empty);
+        let aligned = fs
+            .replace_file("aligned", Buffer::from(noise(2048)))
+            .await?;
+        assert_eq!(aligned.chunk_sizes(), [1024, 1024]);
+        fs.commit().await?;
+
+        let mut fs = Fs::builder(op)
+            .chunk_store(store.clone())
+            .chunk_size(1024)
+            .build()
+            .await?;
+        let manifest = fs.read_metadata().await?;
+        fs.read_manifest(&manifest).await?;
+        assert_eq!(fs.stat("empty").unwrap().size(), 0);
+        let gets = store.gets();
+        assert!(fs.read("empty").await?.is_empty());
+        assert!(fs.read_range("empty", 0..0).await?.is_empty());
+        assert_eq!(store.gets(), gets);
+        assert_eq!(fs.read("aligned").await?.to_vec(), noise(2048));
+        assert!(fs.verify(VerifyOptions::new().deep(true)).await?.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sequential_commits_and_reopen() -> Result<()> {
+        let op = conditional_memory();
+        let mut fs = Fs::init(op.clone()).await?;
+        fs.replace_file("a", Buffer::from("one")).await?;
+        assert_eq!(fs.commit().await?, 1);
+        fs.replace_file("b", Buffer::from("two")).await?;
+        assert_eq!(fs.commit().await?, 2);
+        assert!(fs.check_commit_ready().await?);
+
+        let mut fs = open_latest(op.clone()).await?;
+        assert_eq!(fs.info().version, 2);
+        assert_eq!(paths(&fs), vec!["a", "b"]);
+        assert_eq!(fs.read("b").await?.to_vec(), b"two");
+
+        // The reopened file system keeps committing on the same history.
+        fs.remove_file("a");
+        assert_eq!(fs.commit().await?, 3);
+        let fs = open_latest(op).await?;
+        assert_eq!(fs.info().version, 3);
+        assert_eq!(paths(&fs), vec!["b"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_init_with_options() -> Result<()> {
+        let key = [7u8; 32];
+        let chunking = Chunking::Cdc {
+            min: 256,
+            avg: 512,
+            max: 1024,
+        };
+        for op in [memory(), conditional_memory()] {
+            let mut fs = Fs::builder(op.clone())
+                .chunk_size(1024)
+                .chunking(chunking)
+                .compression(Codec::Zstd { level: 3 })
+                .encryption_key(key)
+                .init()
+                .await?;
+            let content = text(4096);
+            fs.replace_file("a", Buffer::from(content.clone())).await?;
+            fs.commit().await?;
+
+            // The options are recorded by init, so they are adopted on reopen.
+            let fs = Fs::builder(op.clone()).encryption_key(key).build().await?;
+            let info = fs.info();
+            assert_eq!(info.chunk_size, 1024);
+            assert_eq!(info.compression.as_deref(), Some("zstd"));
+            assert!(info.encryption.is_some());
+            assert_eq!(fs.ctx.config.cdc.map(|cdc| cdc.avg), Some(512));
+            assert!(Fs::create(op.clone()).await.is_err());
+
+            let err = Fs::builder(op.clone())
+                .encryption_key(key)
+                .init()
+                .await
+                .err()
+                .unwrap();
+            assert!(matches!(
+                err.downcast_ref::<Error>(),
+                Some(Error::AlreadyExists)
+            ));
+            let mut fs = Fs::builder(op).encryption_key(key).build().await?;
+            let manifest = fs.read_metadata().await?;
+            fs.read_manifest(&manifest).await?;
+            assert_eq!(fs.read("a").await?.to_vec(), content);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_apply_patch_infers_chunk_sizes() -> Result<()> {
+        let mut fs = Fs::builder(memory()).chunk_size(1024).build().await?;
+        let file = fs.replace_file("a", Buffer::from(noise(3000))).await?;
+
+        // Empty chunk sizes are inferred from the chunk size of the fs.
+        let add = PatchFile {
+            path: "b".to_string(),
+            chunks: file.chunks().to_vec(),
+            chunk_sizes: vec![],
+            size: file.size(),
+        };
+        let patch = ManifestPatch {
+            adds: vec![add.clone()],
+            ..Default::default()
+        };
+        fs.apply_patch(patch.clone()).await?;
+        assert_eq!(fs.stat("b").unwrap().chunk_sizes(), [1024, 1024, 952]);
+        assert_eq!(fs.read("b").await?.to_vec(), noise(3000));
+
+        // They can't be inferred for cdc chunking.
+        let mut fs = Fs::builder(memory())
+            .chunking(Chunking::Cdc {
+                min: 256,
+                avg: 1024,
+                max: 4096,
+            })
+            .build()
+            .await?;
+        let err = fs.apply_patch(patch).await.unwrap_err();
+        assert!(err.to_string().contains("required by cdc"), "{err}");
+        assert!(fs.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_merge_latest_without_etag() -> Result<()> {
+        // The memory service doesn't have etags.
+        let op = memory();
+        Fs::init(op.clone()).await?;
+        let mut fs = open_latest(op.clone()).await?;
+        let mut stale = open_latest(op.clone()).await?;
+        fs.replace_file("a", Buffer::from("a")).await?;
+        fs.commit().await?;
+
+        stale.replace_file("b", Buffer::from("b")).await?;
+        let ours = stale.write_manifest().await?;
+        let merged = stale.merge_latest(&ours).await?;
+        assert_eq!(stale.ctx.version(), 1);
+        stale.publish(&merged).await?;
+
+        let fs = open_latest(op).await?;
+        assert_eq!(paths(&fs), vec!["a", "b"]);
+        assert_eq!(fs.ctx.version(), 2);
+        Ok(())
     }
-    let result = hasher.finalize();
-    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(result.as_bytes())
 }
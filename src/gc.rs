@@ -0,0 +1,10 @@
+/// The report of collecting unreferenced chunks.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// The number of chunks that are still referenced.
+    pub live_chunks: usize,
+    /// The number of chunks deleted.
+    pub deleted_chunks: usize,
+    /// The total bytes of deleted chunks.
+    pub reclaimed_bytes: u64,
+}
@@ -0,0 +1,175 @@
+use base64::Engine as _;
+use opendal::Buffer;
+
+/// Segments smaller than this are coalesced before fed to hash states, so
+/// that hashers see slices large enough for their bulk paths.
+const COALESCE_SIZE: usize = 64 * 1024;
+
+/// The algorithm of calculating chunk ids from content.
+///
+/// The name of hasher is recorded in metadata, and a file system can only be
+/// opened with the same hasher that created it.
+pub trait Hasher: Send + Sync + 'static {
+    /// The name of the algorithm, like `blake3`.
+    fn name(&self) -> &str;
+
+    /// The encoding of ids, like `base64-url-nopad`.
+    fn id_encoding(&self) -> &str;
+
+    /// Start hashing a new chunk.
+    fn start(&self) -> Box<dyn HashState>;
+
+    /// Check whether the name is a well-formed id of this hasher.
+    ///
+    /// Ids are used as object names, so the default implementation accepts
+    /// any name except empty ones, `.`, `..` and those containing `/`.
+    fn is_valid_id(&self, name: &str) -> bool {
+        !matches!(name, "" | "." | "..") && !name.contains('/')
+    }
+
+    /// Calculate the id of the content.
+    fn chunk_id(&self, buf: &Buffer) -> String {
+        let mut state = self.start();
+        state.update_buffer(buf);
+        state.finish()
+    }
+}
+
+/// The incremental state of hashing a chunk.
+pub trait HashState: Send {
+    /// Feed the next bytes.
+    fn update(&mut self, data: &[u8]);
+
+    /// Feed all bytes of the buffer.
+    ///
+    /// Large segments are fed as is, and small ones are coalesced into
+    /// larger slices first.
+    fn update_buffer(&mut self, buf: &Buffer) {
+        let mut pending = Vec::new();
+        for bs in buf.clone() {
+            if bs.len() >= COALESCE_SIZE {
+                if !pending.is_empty() {
+                    self.update(&pending);
+                    pending.clear();
+                }
+                self.update(&bs);
+                continue;
+            }
+            if pending.is_empty() {
+                pending.reserve(COALESCE_SIZE.min(buf.len()));
+            }
+            pending.extend_from_slice(&bs);
+            if pending.len() >= COALESCE_SIZE {
+                self.update(&pending);
+                pending.clear();
+            }
+        }
+        if !pending.is_empty() {
+            self.update(&pending);
+        }
+    }
+
+    /// Finish hashing and return the id.
+    fn finish(self: Box<Self>) -> String;
+}
+
+/// The default hasher using blake3 with URL_SAFE_NO_PAD base64 ids.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Blake3Hasher;
+
+impl Hasher for Blake3Hasher {
+    fn name(&self) -> &str {
+        "blake3"
+    }
+
+    fn id_encoding(&self) -> &str {
+        "base64-url-nopad"
+    }
+
+    fn start(&self) -> Box<dyn HashState> {
+        Box::new(blake3::Hasher::new())
+    }
+
+    fn is_valid_id(&self, name: &str) -> bool {
+        matches!(
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(name),
+            Ok(hash) if hash.len() == blake3::OUT_LEN
+        )
+    }
+}
+
+impl HashState for blake3::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(self.finalize().as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    /// A hasher relying on the default id check.
+    struct PlainHasher;
+
+    impl Hasher for PlainHasher {
+        fn name(&self) -> &str {
+            "plain"
+        }
+
+        fn id_encoding(&self) -> &str {
+            "plain"
+        }
+
+        fn start(&self) -> Box<dyn HashState> {
+            Blake3Hasher.start()
+        }
+    }
+
+    #[test]
+    fn test_is_valid_id() {
+        let id = Blake3Hasher.chunk_id(&Buffer::from("hello"));
+        assert!(Blake3Hasher.is_valid_id(&id));
+        assert!(PlainHasher.is_valid_id(&id));
+        for name in ["", ".", "..", "../metadata", "a/b", &id[1..]] {
+            assert!(!Blake3Hasher.is_valid_id(name), "{name} must be rejected");
+        }
+        for name in ["", ".", "..", "../metadata", "a/b"] {
+            assert!(!PlainHasher.is_valid_id(name), "{name} must be rejected");
+        }
+    }
+
+    #[test]
+    fn test_update_buffer_coalesces_segments() {
+        /// A state recording the size of every update.
+        struct Sizes(Vec<usize>, Box<dyn HashState>);
+
+        impl HashState for Sizes {
+            fn update(&mut self, data: &[u8]) {
+                self.0.push(data.len());
+                self.1.update(data);
+            }
+
+            fn finish(self: Box<Self>) -> String {
+                self.1.finish()
+            }
+        }
+
+        let content: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+        let expected = Blake3Hasher.chunk_id(&Buffer::from(content.clone()));
+        let mut segments: Vec<Bytes> = content[..100_000]
+            .chunks(1000)
+            .map(Bytes::copy_from_slice)
+            .collect();
+        segments.push(Bytes::copy_from_slice(&content[100_000..]));
+
+        let mut state = Box::new(Sizes(vec![], Blake3Hasher.start()));
+        state.update_buffer(&segments.into_iter().collect());
+        assert_eq!(state.0, [66_000, 34_000, 200_000]);
+        assert_eq!(state.finish(), expected);
+    }
+}
@@ -0,0 +1,20 @@
+/// The effective configuration of a file system.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsInfo {
+    /// The version of the on-disk format spec.
+    pub spec_version: u32,
+    /// The version of the file system.
+    pub version: usize,
+    /// The size used to split files into chunks.
+    pub chunk_size: u64,
+    /// The hash algorithm of chunk ids, like `blake3`.
+    pub hasher: String,
+    /// The encoding of chunk ids, like `base64-url-nopad`.
+    pub id_encoding: String,
+    /// The codec of chunk content, `None` if not compressed.
+    pub compression: Option<String>,
+    /// The cipher of chunk content, `None` if not encrypted.
+    pub encryption: Option<String>,
+    /// The number of previous versions retained.
+    pub retain_versions: u64,
+}
@@ -1,7 +1,59 @@
+mod audit;
+pub use audit::StorageAudit;
+
+mod chunking;
+pub use chunking::Chunking;
+
+mod cipher;
+
+mod codec;
+pub use codec::Codec;
+
+mod error;
+pub use error::Error;
+
 mod file;
-pub use file::File;
+pub use file::{CloseResult, File, FileWriter, InlinePolicy, WriterState, DEFAULT_CHUNK_SIZE};
 
 mod fs;
-pub use fs::Fs;
+pub use fs::{Fs, FsBuilder};
+
+mod gc;
+pub use gc::GcReport;
+
+mod hasher;
+pub use hasher::{Blake3Hasher, HashState, Hasher};
+
+mod info;
+pub use info::FsInfo;
+
+mod load;
+pub use load::{EntryFilter, LoadOptions, LoadReport, OnError, PathMapper};
+
+#[cfg(feature = "blocking")]
+mod blocking;
+#[cfg(feature = "blocking")]
+pub use blocking::BlockingFs;
+
+mod patch;
+pub use patch::{ManifestPatch, PatchFile};
+
+mod reader;
+pub use reader::FileReader;
+
+mod stats;
+pub use stats::Hotspots;
+
+mod store;
+pub use store::{ChunkStore, OpendalChunkStore};
+
+mod streaming;
+pub use streaming::StreamingFileWriter;
+
+mod verify;
+pub use verify::{VerifyOptions, VerifyReport};
 
 pub(crate) mod specs;
+
+#[cfg(test)]
+mod test_util;
@@ -0,0 +1,297 @@
+use std::fmt::{self, Debug, Formatter};
+use std::sync::Arc;
+
+use opendal::Entry;
+
+/// The policy of handling a file that failed to import during `load_from`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnError {
+    /// Abort the whole import on the first error.
+    #[default]
+    Abort,
+    /// Skip the failed file and record it in the [`LoadReport`].
+    Skip,
+    /// Retry the failed file up to the given times before aborting.
+    Retry(usize),
+}
+
+/// Map the path of source files to the path in the manifest.
+pub type PathMapper = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+/// Decide whether a source entry should be imported.
+pub type EntryFilter = Arc<dyn Fn(&Entry) -> bool + Send + Sync>;
+
+/// Options for `Fs::load_from_with`.
+#[derive(Clone, Default)]
+pub struct LoadOptions {
+    pub(crate) on_error: OnError,
+    pub(crate) path_mapper: Option<PathMapper>,
+    pub(crate) filter: Option<EntryFilter>,
+}
+
+impl Debug for LoadOptions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LoadOptions")
+            .field("on_error", &self.on_error)
+            .field("path_mapper", &self.path_mapper.is_some())
+            .field("filter", &self.filter.is_some())
+            .finish()
+    }
+}
+
+impl LoadOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the policy of handling unreadable files.
+    pub fn on_error(mut self, on_error: OnError) -> Self {
+        self.on_error = on_error;
+        self
+    }
+
+    /// Set the mapper applied to every source path before it becomes the
+    /// path in the manifest.
+    pub fn path_mapper(mut self, f: impl Fn(&str) -> String + Send + Sync + 'static) -> Self {
+        self.path_mapper = Some(Arc::new(f));
+        self
+    }
+
+    /// Strip the given prefix and leading slashes from source paths.
+    ///
+    /// The prefix is only stripped at a path boundary, so prefix `data`
+    /// strips `data/a` into `a` but keeps `database/a` as is.
+    pub fn strip_prefix(self, prefix: &str) -> Self {
+        let prefix = prefix.trim_matches('/').to_string();
+        self.path_mapper(move |path| {
+            let path = path.trim_start_matches('/');
+            match path.strip_prefix(prefix.as_str()) {
+                Some(rest) if !prefix.is_empty() && (rest.is_empty() || rest.starts_with('/')) => {
+                    rest.trim_start_matches('/').to_string()
+                }
+                _ => path.to_string(),
+            }
+        })
+    }
+
+    /// Set the filter consulted for every source entry, entries returning
+    /// `false` are neither read nor chunked.
+    pub fn filter(mut self, f: impl Fn(&Entry) -> bool + Send + Sync + 'static) -> Self {
+        self.filter = Some(Arc::new(f));
+        self
+    }
+
+    /// Check whether the entry should be imported.
+    pub(crate) fn accept(&self, entry: &Entry) -> bool {
+        match &self.filter {
+            Some(f) => f(entry),
+            None => true,
+        }
+    }
+
+    /// Map the source path to the path in the manifest.
+    pub(crate) fn map_path(&self, path: &str) -> String {
+        match &self.path_mapper {
+            Some(f) => f(path),
+            None => path.to_string(),
+        }
+    }
+}
+
+/// The report of a `load_from` import.
+#[derive(Debug, Default)]
+pub struct LoadReport {
+    /// Paths of files that have been imported.
+    pub loaded: Vec<String>,
+    /// Paths of files that have been skipped along with their errors.
+    pub failed: Vec<(String, anyhow::Error)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{flaky_memory, memory, unlistable_memory};
+    use crate::Fs;
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn test_skip_unreadable_files() -> Result<()> {
+        let external = memory();
+        external.write("a", "a").await?;
+        external.write("c", "c").await?;
+        let paths = ["a", "b", "c"].map(String::from);
+
+        let mut fs = Fs::create(memory()).await?;
+        let opts = LoadOptions::new().on_error(OnError::Skip);
+        let report = fs.load_paths_with(external, &paths, opts).await?;
+        assert_eq!(report.loaded, vec!["a", "c"]);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, "b");
+        assert_eq!(
+            fs.list().map(|file| file.path()).collect::<Vec<_>>(),
+            vec!["a", "c"]
+        );
+        assert_eq!(fs.read("c").await?.to_vec(), b"c");
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_prefix() {
+        let opts = LoadOptions::new().strip_prefix("/data/");
+        for (path, expected) in [
+            ("/data/a", "a"),
+            ("data/dir/b", "dir/b"),
+            ("database/a", "database/a"),
+            ("/other/a", "other/a"),
+            ("a", "a"),
+        ] {
+            assert_eq!(opts.map_path(path), expected);
+        }
+        assert_eq!(LoadOptions::new().strip_prefix("").map_path("/a"), "a");
+    }
+
+    #[tokio::test]
+    async fn test_load_leading_slash_paths() -> Result<()> {
+        let external = memory();
+        external.write("data/a", "a").await?;
+        external.write("database/b", "b").await?;
+        let paths = ["/data/a", "/database/b"].map(String::from);
+
+        let mut fs = Fs::create(memory()).await?;
+        let opts = LoadOptions::new().strip_prefix("data");
+        let report = fs.load_paths_with(external, &paths, opts).await?;
+        assert_eq!(report.loaded, paths);
+        assert_eq!(
+            fs.list().map(|file| file.path()).collect::<Vec<_>>(),
+            vec!["a", "database/b"]
+        );
+        assert_eq!(fs.read("a").await?.to_vec(), b"a");
+        assert_eq!(fs.read("database/b").await?.to_vec(), b"b");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_nested_directories() -> Result<()> {
+        let external = memory();
+        for path in ["a", "x/b", "x/y/c", "x/y/z/d"] {
+            external.write(path, path.to_string()).await?;
+        }
+        external.create_dir("empty/").await?;
+
+        let op = memory();
+        let mut fs = Fs::create(op.clone()).await?;
+        fs.load_from(external).await?;
+        let mut fs = Fs::create(op).await?;
+        let manifest = fs.read_metadata().await?;
+        fs.read_manifest(&manifest).await?;
+        assert_eq!(
+            fs.list().map(|file| file.path()).collect::<Vec<_>>(),
+            vec!["a", "x/b", "x/y/c", "x/y/z/d"]
+        );
+        assert_eq!(fs.read("x/y/z/d").await?.to_vec(), b"x/y/z/d");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_filter_entries() -> Result<()> {
+        let external = memory();
+        for path in [
+            "a.txt",
+            "dir/b.txt",
+            "dir/c.tmp",
+            ".DS_Store",
+            "dir/.DS_Store",
+        ] {
+            external.write(path, path.to_string()).await?;
+        }
+
+        let mut fs = Fs::create(memory()).await?;
+        let opts = LoadOptions::new().filter(|entry| {
+            let name = entry.name();
+            name != ".DS_Store" && !name.ends_with(".tmp")
+        });
+        let report = fs.load_from_with(external, opts).await?;
+        assert_eq!(report.loaded, vec!["a.txt", "dir/b.txt"]);
+        assert_eq!(
+            fs.list().map(|file| file.path()).collect::<Vec<_>>(),
+            vec!["a.txt", "dir/b.txt"]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_paths_without_list() -> Result<()> {
+        let external = unlistable_memory();
+        external.write("a", "a").await?;
+        external.write("dir/b", "b").await?;
+        assert!(external.list("/").await.is_err());
+
+        let mut fs = Fs::create(memory()).await?;
+        assert!(fs.load_from(external.clone()).await.is_err());
+        let paths = ["a", "dir/b"].map(String::from);
+        fs.load_paths(external, &paths).await?;
+        assert_eq!(
+            fs.list().map(|file| file.path()).collect::<Vec<_>>(),
+            vec!["a", "dir/b"]
+        );
+        assert_eq!(fs.read("dir/b").await?.to_vec(), b"b");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_abort_on_unreadable_files() -> Result<()> {
+        let external = memory();
+        external.write("a", "a").await?;
+        let paths = ["a", "b"].map(String::from);
+
+        for on_error in [OnError::Abort, OnError::Retry(2)] {
+            let mut fs = Fs::create(memory()).await?;
+            let opts = LoadOptions::new().on_error(on_error);
+            let res = fs.load_paths_with(external.clone(), &paths, opts).await;
+            assert!(res.is_err(), "{on_error:?} must abort");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient_failures() -> Result<()> {
+        let paths = ["a", "b", "c"].map(String::from);
+        let flaky = |failures| async move {
+            let external = flaky_memory("b", failures);
+            for path in ["a", "b", "c"] {
+                external.write(path, path).await?;
+            }
+            Ok::<_, anyhow::Error>(external)
+        };
+
+        // Failures within the retries are recovered.
+        let mut fs = Fs::create(memory()).await?;
+        let opts = LoadOptions::new().on_error(OnError::Retry(2));
+        let report = fs.load_paths_with(flaky(2).await?, &paths, opts).await?;
+        assert_eq!(report.loaded, paths);
+        assert!(report.failed.is_empty());
+        assert_eq!(fs.read("b").await?.to_vec(), b"b");
+
+        let mut fs = Fs::create(memory()).await?;
+        let opts = LoadOptions::new().on_error(OnError::Retry(1));
+        assert!(fs
+            .load_paths_with(flaky(2).await?, &paths, opts)
+            .await
+            .is_err());
+
+        // Skipped files are reported with their paths.
+        let mut fs = Fs::create(memory()).await?;
+        let opts = LoadOptions::new().on_error(OnError::Skip);
+        let report = fs.load_paths_with(flaky(1).await?, &paths, opts).await?;
+        assert_eq!(report.loaded, vec!["a", "c"]);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, "b");
+        assert!(report.failed[0]
+            .1
+            .to_string()
+            .contains("injected read failure"));
+        assert!(!fs.contains("b"));
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+use anyhow::Result;
+use bincode::{Decode, Encode};
+
+/// A file to add in a [`ManifestPatch`].
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct PatchFile {
+    pub path: String,
+    pub chunks: Vec<String>,
+    /// The size of every chunk.
+    ///
+    /// If empty, the file is assumed to be split by the chunk size of the
+    /// file system it's applied to. It's required for file systems chunked
+    /// by CDC.
+    pub chunk_sizes: Vec<u64>,
+    pub size: u64,
+}
+
+/// A set of changes computed out of band to apply to the manifest.
+///
+/// Changes are applied in the order of removes, renames and adds.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Encode, Decode)]
+pub struct ManifestPatch {
+    pub adds: Vec<PatchFile>,
+    pub removes: Vec<String>,
+    /// Renames as `(from, to)`.
+    pub renames: Vec<(String, String)>,
+    /// Check that all chunks referenced by adds exist before applying.
+    pub verify_chunks: bool,
+}
+
+impl ManifestPatch {
+    /// Encode the patch into bytes.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(bincode::encode_to_vec(self, bincode::config::standard())?)
+    }
+
+    /// Decode the patch from bytes.
+    pub fn from_bytes(bs: &[u8]) -> Result<Self> {
+        let (patch, _) = bincode::decode_from_slice(bs, bincode::config::standard())?;
+        Ok(patch)
+    }
+}
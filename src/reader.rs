@@ -0,0 +1,300 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::{fs::FsContext, File};
+use anyhow::anyhow;
+use anyhow::Result;
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use opendal::Buffer;
+
+/// The reader of a file in the file system.
+pub struct FileReader {
+    ctx: Arc<FsContext>,
+    file: File,
+    concurrency: usize,
+}
+
+impl FileReader {
+    pub(crate) fn new(ctx: Arc<FsContext>, file: File) -> Self {
+        let concurrency = ctx.read_concurrency();
+        Self {
+            ctx,
+            file,
+            concurrency,
+        }
+    }
+
+    /// The file to read.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Read the whole content of the file.
+    ///
+    /// Empty files are returned without any storage access. Every chunk is
+    /// checked against its recorded size.
+    pub async fn read_all(&self) -> Result<Buffer> {
+        if self.file.size() == 0 {
+            return Ok(Buffer::new());
+        }
+        if let Some(inline) = self.file.inline() {
+            return Ok(Buffer::from(inline.clone()));
+        }
+        check_total_size(&self.file)?;
+
+        let mut bufs = Vec::with_capacity(self.file.chunks().len());
+        for idx in 0..self.file.chunks().len() {
+            bufs.push(read_chunk(&self.ctx, &self.file, idx).await?);
+        }
+        Ok(bufs.into_iter().flatten().collect())
+    }
+
+    /// Convert into a stream yielding the content chunk by chunk.
+    ///
+    /// Only one chunk is held in memory at a time, so it's suitable for
+    /// large files. Every chunk is checked against its recorded size like
+    /// [`FileReader::read_all`].
+    pub fn into_stream(self) -> impl Stream<Item = Result<Buffer>> {
+        let ctx = self.ctx;
+        let inline = self.file.inline().cloned().map(|bs| Ok(Buffer::from(bs)));
+        let invalid = match inline {
+            None => check_total_size(&self.file).err().map(Err),
+            Some(_) => None,
+        };
+        let chunks = match invalid {
+            None => 0..self.file.chunks().len(),
+            Some(_) => 0..0,
+        };
+        let file = Arc::new(self.file);
+        stream::iter(inline)
+            .chain(stream::iter(invalid))
+            .chain(stream::iter(chunks).then(move |idx| {
+                let (ctx, file) = (ctx.clone(), file.clone());
+                async move { read_chunk(&ctx, &file, idx).await }
+            }))
+    }
+
+    /// The index of the chunk containing the byte at `offset`.
+    ///
+    /// Returns `None` if the offset is at or past the end of the file, or the
+    /// file is stored inline.
+    pub fn chunk_index(&self, offset: u64) -> Option<usize> {
+        let offsets = self.file.offsets();
+        if offset >= offsets[offsets.len() - 1] {
+            return None;
+        }
+        Some(offsets.partition_point(|&start| start <= offset) - 1)
+    }
+
+    /// Read multiple ranges of the file at once.
+    ///
+    /// Every range is `(start, end)` with `end` excluded. All chunks needed
+    /// by the ranges are fetched at most once, up to the read concurrency of
+    /// the file system at the same time, and a buffer is returned for every
+    /// input range in the same order.
+    pub async fn read_ranges(&self, ranges: &[(u64, u64)]) -> Result<Vec<Buffer>> {
+        for &(start, end) in ranges {
+            if start > end || end > self.file.size() {
+                return Err(anyhow!(
+                    "range {start}..{end} is out of file {} with size {}",
+                    self.file.path(),
+                    self.file.size()
+                ));
+            }
+        }
+
+        if let Some(inline) = self.file.inline() {
+            return Ok(ranges
+                .iter()
+                .map(|&(start, end)| Buffer::from(inline.slice(start as usize..end as usize)))
+                .collect());
+        }
+
+        let offsets = self.file.offsets();
+        let mut needed = BTreeSet::new();
+        for &(start, end) in ranges {
+            needed.extend(chunks_in_range(offsets, start, end));
+        }
+
+        let chunks: BTreeMap<usize, Buffer> = stream::iter(needed)
+            .map(|idx| async move {
+                let buf = read_chunk(&self.ctx, &self.file, idx).await?;
+                Ok::<_, anyhow::Error>((idx, buf))
+            })
+            .buffer_unordered(self.concurrency)
+            .try_collect()
+            .await?;
+
+        Ok(ranges
+            .iter()
+            .map(|&(start, end)| {
+                chunks_in_range(offsets, start, end)
+                    .flat_map(|idx| {
+                        let chunk_start = offsets[idx];
+                        let from = start.saturating_sub(chunk_start) as usize;
+                        let to = (end.min(offsets[idx + 1]) - chunk_start) as usize;
+                        chunks[&idx].slice(from..to)
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+}
+
+/// Check that chunk sizes of the file sum to its size, so that reading
+/// every chunk by its size yields the whole file.
+fn check_total_size(file: &File) -> Result<()> {
+    if file.chunk_sizes().len() != file.chunks().len() {
+        return Err(anyhow!(
+            "file {} has {} chunks but {} chunk sizes",
+            file.path(),
+            file.chunks().len(),
+            file.chunk_sizes().len()
+        ));
+    }
+    let total: u64 = file.chunk_sizes().iter().sum();
+    if total != file.size() {
+        return Err(anyhow!(
+            "file {} has size {} but chunks sum to {total}",
+            file.path(),
+            file.size()
+        ));
+    }
+    Ok(())
+}
+
+/// Read the chunk at `idx` of the file, checking that its content has the
+/// recorded chunk size.
+async fn read_chunk(ctx: &FsContext, file: &File, idx: usize) -> Result<Buffer> {
+    let buf = ctx.read_file_chunk(file, idx).await?;
+    let expected = file.chunk_sizes()[idx];
+    if buf.len() as u64 != expected {
+        return Err(anyhow!(
+            "chunk {} of file {} has {} bytes, expect {expected}",
+            file.chunks()[idx],
+            file.path(),
+            buf.len()
+        ));
+    }
+    Ok(buf)
+}
+
+/// The indexes of chunks overlapping with the range.
+fn chunks_in_range(offsets: &[u64], start: u64, end: u64) -> Range<usize> {
+    if start == end {
+        return 0..0;
+    }
+    let first = offsets.partition_point(|&offset| offset <= start) - 1;
+    let last = offsets.partition_point(|&offset| offset < end);
+    first..last.max(first)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{memory, noise, MemoryChunkStore};
+    use crate::Fs;
+    use chrono::Utc;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn test_read_ranges_fetch_once() -> Result<()> {
+        let store = MemoryChunkStore::default();
+        let mut fs = Fs::builder(memory())
+            .chunk_store(store.clone())
+            .chunk_size(1024)
+            .build()
+            .await?;
+        let content = noise(4096);
+        fs.replace_file("a", Buffer::from(content.clone())).await?;
+        let reader = fs.open("a")?;
+
+        let gets = store.gets();
+        let ranges = [(100, 300), (200, 400), (0, 1024), (500, 500)];
+        let bufs = reader.read_ranges(&ranges).await?;
+        assert_eq!(store.gets() - gets, 1);
+        for (buf, (start, end)) in bufs.iter().zip(ranges) {
+            assert_eq!(buf.to_vec(), content[start as usize..end as usize]);
+        }
+
+        // Ranges across chunks fetch every chunk they touch once.
+        let gets = store.gets();
+        let bufs = reader.read_ranges(&[(1000, 2100), (2050, 2060)]).await?;
+        assert_eq!(store.gets() - gets, 3);
+        assert_eq!(bufs[0].to_vec(), content[1000..2100]);
+        assert!(reader.read_ranges(&[(0, 4097)]).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_ranges_checks_chunk_size() -> Result<()> {
+        let mut fs = Fs::builder(memory()).chunk_size(1024).build().await?;
+        let file = fs.replace_file("a", Buffer::from(noise(2048))).await?;
+
+        // The recorded size of the second chunk is wrong.
+        let broken = File::new(
+            "b".to_string(),
+            file.chunks().to_vec(),
+            vec![1024, 1000],
+            vec![],
+            Utc::now(),
+        );
+        fs.insert_file(broken);
+        let reader = fs.open("b")?;
+        assert_eq!(reader.read_ranges(&[(0, 100)]).await?[0].len(), 100);
+        let err = reader.read_ranges(&[(1500, 1600)]).await.unwrap_err();
+        assert!(err.to_string().contains("expect 1000"), "{err}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chunk_index_reuses_offsets() -> Result<()> {
+        let mut fs = Fs::builder(memory()).chunk_size(64).build().await?;
+        let content = noise(64 * 1000 + 10);
+        fs.replace_file("a", Buffer::from(content.clone())).await?;
+
+        let reader = fs.open("a")?;
+        assert_eq!(reader.file().chunks().len(), 1001);
+        for (offset, idx) in [(0, 0), (63, 0), (64, 1), (31_999, 499), (64_009, 1000)] {
+            assert_eq!(reader.chunk_index(offset), Some(idx));
+        }
+        assert_eq!(reader.chunk_index(64_010), None);
+
+        // Offsets are computed once per file and shared by later readers.
+        let again = fs.open("a")?;
+        assert!(std::ptr::eq(
+            reader.file().offsets(),
+            again.file().offsets()
+        ));
+        assert!(std::ptr::eq(
+            fs.stat("a").unwrap().offsets(),
+            again.file().offsets()
+        ));
+        assert_eq!(
+            fs.read_range("a", 31_990..32_010).await?.to_vec(),
+            content[31_990..32_010]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_ranges_concurrency() -> Result<()> {
+        for concurrency in [1, 3] {
+            let store = MemoryChunkStore::default();
+            let mut fs = Fs::builder(memory())
+                .chunk_store(store.clone())
+                .chunk_size(1024)
+                .read_concurrency(concurrency)
+                .build()
+                .await?;
+            let content = noise(8192);
+            fs.replace_file("a", Buffer::from(content.clone())).await?;
+
+            let bufs = fs.open("a")?.read_ranges(&[(0, 8192)]).await?;
+            assert_eq!(bufs[0].to_vec(), content);
+            assert_eq!(store.max_pending_gets(), concurrency);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+use bincode::{Decode, Encode};
+
+use super::v1;
+
+/// The magic prefix of encoded [`Metadata`].
+///
+/// Metadata without it is decoded as [`v1::Metadata`].
+pub const METADATA_MAGIC: &[u8] = b"EPOCHFS-METADATA-V2\0";
+
+/// The magic prefix of an encoded [`Manifest`].
+///
+/// Manifests without it are decoded as [`v1::Manifest`].
+pub const MANIFEST_MAGIC: &[u8] = b"EPOCHFS-MANIFEST-V2\0";
+
+/// Metadata for the file system.
+#[derive(Encode, Decode, PartialEq, Debug)]
+pub struct Metadata {
+    /// The version of the given fs.
+    pub version: usize,
+
+    /// The path to the latest snapshot.
+    pub manifest: String,
+    /// The last modified time of the latest snapshot.
+    pub last_modified: u64,
+    /// The configuration used to write the file system.
+    pub config: Config,
+    /// Previous snapshots retained by `config.retain_versions`, the newest
+    /// first.
+    pub history: Vec<Snapshot>,
+}
+
+impl From<v1::Metadata> for Metadata {
+    /// File systems of spec v1 were always written with the default config.
+    fn from(value: v1::Metadata) -> Self {
+        Self {
+            version: value.version,
+            manifest: value.manifest,
+            last_modified: value.last_modified,
+            config: Config {
+                chunk_headers: false,
+                ..Default::default()
+            },
+            history: vec![],
+        }
+    }
+}
+
+/// A previous snapshot of the file system.
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+pub struct Snapshot {
+    pub version: usize,
+    pub manifest: String,
+    pub last_modified: u64,
+}
+
+/// The configuration recorded in metadata.
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+pub struct Config {
+    /// The size used to split files into chunks.
+    pub chunk_size: u64,
+    /// The sizes of content defined chunking, `None` if files are split at
+    /// every `chunk_size`.
+    pub cdc: Option<Cdc>,
+    /// The hash algorithm of chunk ids.
+    pub hasher: String,
+    /// The encoding of chunk id from the hash.
+    pub id_encoding: String,
+    /// The codec of chunk content, `None` if not compressed.
+    pub compression: Option<String>,
+    /// The level of the compression codec.
+    pub compression_level: i32,
+    /// Whether every chunk starts with a header byte of its codec and
+    /// cipher.
+    ///
+    /// Chunks of file systems upgraded from spec v1 are stored as is.
+    pub chunk_headers: bool,
+    /// The cipher of chunk content, `None` if not encrypted.
+    pub encryption: Option<String>,
+    /// The number of previous snapshots to retain in metadata.
+    pub retain_versions: u64,
+}
+
+/// The sizes of content defined chunking.
+#[derive(Encode, Decode, PartialEq, Debug, Clone, Copy)]
+pub struct Cdc {
+    pub min: u64,
+    pub avg: u64,
+    pub max: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            chunk_size: crate::file::DEFAULT_CHUNK_SIZE as u64,
+            cdc: None,
+            hasher: "blake3".to_string(),
+            id_encoding: "base64-url-nopad".to_string(),
+            compression: None,
+            compression_level: 0,
+            chunk_headers: true,
+            encryption: None,
+            retain_versions: 0,
+        }
+    }
+}
+
+/// The magic prefix of an encoded [`ManifestIndex`].
+///
+/// Manifests without it are single chunk manifests.
+pub const MANIFEST_INDEX_MAGIC: &[u8] = b"EPOCHFS-MANIFEST-INDEX-V1\0";
+
+/// The index of a manifest split across multiple segments.
+///
+/// Every segment is an encoded [`Manifest`] with [`MANIFEST_MAGIC`], and
+/// files are in path order across segments.
+#[derive(Encode, Decode, PartialEq, Debug)]
+pub struct ManifestIndex {
+    pub segments: Vec<Segment>,
+}
+
+/// A segment of a [`ManifestIndex`].
+#[derive(Encode, Decode, PartialEq, Debug)]
+pub struct Segment {
+    pub id: String,
+    /// The path of the first file in the segment.
+    pub first: String,
+    /// The path of the last file in the segment.
+    pub last: String,
+}
+
+/// A manifest of the file system.
+#[derive(Encode, Decode, PartialEq, Debug)]
+pub struct Manifest {
+    pub files: Vec<File>,
+}
+
+/// A file in the file system.
+#[derive(Encode, Decode, PartialEq, Debug)]
+pub struct File {
+    pub path: String,
+    pub chunks: Vec<String>,
+    /// The size of every chunk, files are assumed to be split by the default
+    /// chunk size if empty.
+    pub chunk_sizes: Vec<u64>,
+    /// The size of every stored chunk object, which differs from the chunk
+    /// size if the chunk is compressed or encrypted. Empty if unknown.
+    pub stored_sizes: Vec<u64>,
+    /// The content of the file if it's stored inline instead of chunks.
+    pub inline: Option<Vec<u8>>,
+    /// The logical format of the file, like `parquet` or `json`.
+    pub format: Option<String>,
+
+    pub size: u64,
+    pub last_modified: u64,
+}
@@ -0,0 +1,9 @@
+/// The hotspots of a file system snapshot.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Hotspots {
+    /// The largest files as `(path, size)`, ordered by size descending.
+    pub largest_files: Vec<(String, u64)>,
+    /// The most referenced chunks as `(chunk_id, references)`, ordered by
+    /// references descending.
+    pub hot_chunks: Vec<(String, usize)>,
+}
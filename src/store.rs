@@ -0,0 +1,198 @@
+use anyhow::Result;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::{stream, FutureExt, StreamExt, TryStreamExt};
+use opendal::{Buffer, ErrorKind, Operator};
+
+/// The storage of content addressed chunks.
+///
+/// Chunks are immutable and identified by their chunk id, so implementations
+/// don't need to handle concurrent writes to the same id.
+pub trait ChunkStore: Send + Sync + 'static {
+    /// Put the chunk to the storage.
+    fn put<'a>(&'a self, chunk_id: &'a str, buf: Buffer) -> BoxFuture<'a, Result<()>>;
+
+    /// Get the chunk from the storage, returns `None` if not exists.
+    fn get<'a>(&'a self, chunk_id: &'a str) -> BoxFuture<'a, Result<Option<Buffer>>>;
+
+    /// Check whether the chunk exists in the storage.
+    fn exists<'a>(&'a self, chunk_id: &'a str) -> BoxFuture<'a, Result<bool>>;
+
+    /// Get the stored size of the chunk, returns `None` if not exists.
+    ///
+    /// The default implementation reads the whole chunk, implementations
+    /// should override it with a cheaper call if possible.
+    fn size<'a>(&'a self, chunk_id: &'a str) -> BoxFuture<'a, Result<Option<u64>>> {
+        async move { Ok(self.get(chunk_id).await?.map(|buf| buf.len() as u64)) }.boxed()
+    }
+
+    /// Delete the chunk from the storage, deleting a missing chunk is not an error.
+    fn delete<'a>(&'a self, chunk_id: &'a str) -> BoxFuture<'a, Result<()>>;
+
+    /// Delete chunks in batch, deleting missing chunks is not an error.
+    ///
+    /// The default implementation deletes chunks one by one.
+    fn delete_batch<'a>(&'a self, chunk_ids: &'a [String]) -> BoxFuture<'a, Result<()>> {
+        async move {
+            for chunk_id in chunk_ids {
+                self.delete(chunk_id).await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    /// List the ids of all chunks in the storage.
+    fn list(&self) -> BoxStream<'_, Result<String>>;
+}
+
+/// The chunk store backed by an opendal operator.
+///
+/// Chunks are stored at `{root}/{chunk_id}`.
+pub struct OpendalChunkStore {
+    op: Operator,
+    root: String,
+}
+
+impl OpendalChunkStore {
+    pub fn new(op: Operator, root: &str) -> Self {
+        Self {
+            op,
+            root: root.trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn chunk_path(&self, chunk_id: &str) -> String {
+        format!("{}/{}", self.root, chunk_id)
+    }
+}
+
+impl ChunkStore for OpendalChunkStore {
+    fn put<'a>(&'a self, chunk_id: &'a str, buf: Buffer) -> BoxFuture<'a, Result<()>> {
+        async move {
+            self.op.write(&self.chunk_path(chunk_id), buf).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn get<'a>(&'a self, chunk_id: &'a str) -> BoxFuture<'a, Result<Option<Buffer>>> {
+        async move {
+            match self.op.read(&self.chunk_path(chunk_id)).await {
+                Ok(buf) => Ok(Some(buf)),
+                Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err.into()),
+            }
+        }
+        .boxed()
+    }
+
+    fn exists<'a>(&'a self, chunk_id: &'a str) -> BoxFuture<'a, Result<bool>> {
+        async move {
+            match self.op.stat(&self.chunk_path(chunk_id)).await {
+                Ok(_) => Ok(true),
+                Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+                Err(err) => Err(err.into()),
+            }
+        }
+        .boxed()
+    }
+
+    fn size<'a>(&'a self, chunk_id: &'a str) -> BoxFuture<'a, Result<Option<u64>>> {
+        async move {
+            match self.op.stat(&self.chunk_path(chunk_id)).await {
+                Ok(meta) => Ok(Some(meta.content_length())),
+                Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err.into()),
+            }
+        }
+        .boxed()
+    }
+
+    fn delete<'a>(&'a self, chunk_id: &'a str) -> BoxFuture<'a, Result<()>> {
+        async move {
+            self.op.delete(&self.chunk_path(chunk_id)).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn delete_batch<'a>(&'a self, chunk_ids: &'a [String]) -> BoxFuture<'a, Result<()>> {
+        let paths: Vec<String> = chunk_ids.iter().map(|id| self.chunk_path(id)).collect();
+        async move {
+            self.op.delete_iter(paths).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn list(&self) -> BoxStream<'_, Result<String>> {
+        let root = format!("{}/", self.root);
+        stream::once(async move { self.op.lister(&root).await })
+            .try_flatten()
+            .try_filter_map(|entry| async move {
+                if entry.metadata().is_dir() {
+                    return Ok(None);
+                }
+                Ok(Some(entry.name().to_string()))
+            })
+            .map_err(anyhow::Error::from)
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{memory, text, MemoryChunkStore};
+    use crate::Fs;
+    use pretty_assertions::assert_eq;
+    use std::collections::BTreeSet;
+
+    #[tokio::test]
+    async fn test_list_many_chunks() -> Result<()> {
+        let op = memory();
+        let store = OpendalChunkStore::new(op.clone(), "data/");
+        let ids: BTreeSet<String> = (0..3000).map(|i| format!("chunk-{i}")).collect();
+        for id in &ids {
+            store.put(id, Buffer::from(id.clone().into_bytes())).await?;
+        }
+        op.write("data/nested/object", "not a chunk").await?;
+        op.write("other/chunk-x", "outside the root").await?;
+
+        let listed: Vec<String> = store.list().try_collect().await?;
+        assert_eq!(listed.len(), ids.len());
+        assert_eq!(listed.into_iter().collect::<BTreeSet<_>>(), ids);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fs_on_memory_chunk_store() -> Result<()> {
+        let op = memory();
+        let store = MemoryChunkStore::default();
+        let mut fs = Fs::builder(op.clone())
+            .chunk_store(store.clone())
+            .chunk_size(1024)
+            .build()
+            .await?;
+        fs.replace_file("a", Buffer::from(text(4096))).await?;
+        fs.replace_file("b", Buffer::from(text(1024))).await?;
+        fs.commit().await?;
+        fs.remove_file("b");
+        fs.commit().await?;
+
+        // Chunks never touch the operator, only metadata does.
+        assert!(op.list_with("data/").await?.is_empty());
+        let mut fs = Fs::builder(op).chunk_store(store.clone()).build().await?;
+        let manifest = fs.read_metadata().await?;
+        fs.read_manifest(&manifest).await?;
+        assert_eq!(fs.read("a").await?.to_vec(), text(4096));
+
+        let before = store.ids().len();
+        let report = fs.gc().await?;
+        assert_eq!(report.deleted_chunks, 1);
+        assert_eq!(store.ids().len(), before - 1);
+        assert_eq!(fs.read("a").await?.to_vec(), text(4096));
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+use std::{mem, sync::Arc};
+
+use crate::chunking::Cutter;
+use crate::file::CloseResult;
+use crate::fs::{update_nonblocking, FsContext};
+use crate::hasher::HashState;
+use crate::{Error, File};
+use anyhow::Result;
+use bytes::Buf as _;
+use chrono::Utc;
+use futures::StreamExt as _;
+use opendal::{Buffer, BufferStream};
+
+/// A chunk that is being hashed on the fly.
+struct PendingChunk {
+    parts: Vec<Buffer>,
+    hasher: Box<dyn HashState>,
+    cutter: Cutter,
+    size: usize,
+}
+
+/// A file writer that hashes bytes on the fly without re-buffering them.
+///
+/// Written buffers are kept as is until the chunker cuts the current chunk,
+/// which is then put into the chunk store once by its precomputed id. At
+/// most one chunk is held in memory, and chunk ids are the same as the ones
+/// produced by [`crate::FileWriter`].
+///
+/// Files written by this writer are always stored as chunks.
+pub struct StreamingFileWriter {
+    ctx: Arc<FsContext>,
+    path: String,
+
+    total_size: u64,
+    chunks: Vec<String>,
+    chunk_sizes: Vec<u64>,
+    stored_sizes: Vec<u64>,
+    new_chunks: usize,
+    dedup_chunks: usize,
+
+    current: Option<PendingChunk>,
+    /// Whether an upload has failed, see [`Error::Poisoned`].
+    poisoned: bool,
+}
+
+impl StreamingFileWriter {
+    pub fn new(ctx: Arc<FsContext>, path: String) -> Self {
+        Self {
+            ctx,
+            path,
+
+            total_size: 0,
+            chunks: vec![],
+            chunk_sizes: vec![],
+            stored_sizes: vec![],
+            new_chunks: 0,
+            dedup_chunks: 0,
+            current: None,
+            poisoned: false,
+        }
+    }
+
+    /// Write bytes into the writer.
+    ///
+    /// If an upload fails, the writer is poisoned and every following call
+    /// returns [`Error::Poisoned`].
+    pub async fn write(&mut self, mut buf: Buffer) -> Result<()> {
+        self.check_poisoned()?;
+        while !buf.is_empty() {
+            let mut pending = self.current.take().unwrap_or_else(|| PendingChunk {
+                parts: vec![],
+                hasher: self.ctx.hasher().start(),
+                cutter: Cutter::new(self.ctx.chunker()),
+                size: 0,
+            });
+
+            let (mut n, mut cut) = (0, false);
+            for bs in buf.clone() {
+                match pending.cutter.feed(&bs) {
+                    Some(k) => {
+                        n += k;
+                        cut = true;
+                        break;
+                    }
+                    None => n += bs.len(),
+                }
+            }
+            let part = buf.slice(..n);
+            pending.hasher = update_nonblocking(pending.hasher, part.clone())
+                .await
+                .inspect_err(|_| self.poisoned = true)?;
+            pending.parts.push(part);
+            pending.size += n;
+            buf.advance(n);
+            self.current = Some(pending);
+
+            if cut {
+                self.finish_chunk().await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn write_from_stream(&mut self, mut s: BufferStream) -> Result<()> {
+        while let Some(buf) = s.next().await {
+            self.write(buf?).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn close(&mut self) -> Result<CloseResult> {
+        self.check_poisoned()?;
+        self.finish_chunk().await?;
+        let file = File::new(
+            self.path.clone(),
+            mem::take(&mut self.chunks),
+            mem::take(&mut self.chunk_sizes),
+            mem::take(&mut self.stored_sizes),
+            Utc::now(),
+        );
+        Ok(CloseResult {
+            file,
+            new_chunks: mem::take(&mut self.new_chunks),
+            dedup_chunks: mem::take(&mut self.dedup_chunks),
+            pin: None,
+        })
+    }
+
+    /// Put the current chunk into the chunk store.
+    ///
+    /// Chunks go through the same dedup and verification as the ones of
+    /// [`crate::FileWriter`].
+    async fn finish_chunk(&mut self) -> Result<()> {
+        let Some(pending) = self.current.take() else {
+            return Ok(());
+        };
+
+        let chunk_id = pending.hasher.finish();
+        let buf: Buffer = pending.parts.into_iter().flatten().collect();
+        let (is_new, stored_size) = self
+            .ctx
+            .put_chunk(&chunk_id, buf)
+            .await
+            .inspect_err(|_| self.poisoned = true)?;
+        if is_new {
+            self.new_chunks += 1;
+        } else {
+            self.dedup_chunks += 1;
+        }
+
+        self.total_size += pending.size as u64;
+        self.chunks.push(chunk_id);
+        self.chunk_sizes.push(pending.size as u64);
+        self.stored_sizes.push(stored_size);
+        Ok(())
+    }
+
+    /// Return [`Error::Poisoned`] if an upload has failed.
+    fn check_poisoned(&self) -> Result<()> {
+        if self.poisoned {
+            return Err(Error::Poisoned {
+                path: self.path.clone(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{memory, noise, MemoryChunkStore};
+    use crate::Fs;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn test_same_ids_as_file_writer() -> Result<()> {
+        let op = memory();
+        let fs = Fs::builder(op.clone()).chunk_size(4096).build().await?;
+        let content = Buffer::from(noise(100_000));
+
+        let mut writer = fs.new_streaming_writer("a");
+        for i in (0..content.len()).step_by(1000) {
+            writer
+                .write(content.slice(i..(i + 1000).min(content.len())))
+                .await?;
+        }
+        let streamed = writer.close().await?;
+        assert_eq!(streamed.file.size(), 100_000);
+        assert_eq!(streamed.file.chunk_sizes().iter().sum::<u64>(), 100_000);
+
+        let mut writer = fs.new_file_writer("b");
+        writer.write(content.clone()).await?;
+        let buffered = writer.close().await?;
+        assert_eq!(streamed.file.chunks(), buffered.file.chunks());
+        assert_eq!(buffered.new_chunks, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dedup_like_file_writer() -> Result<()> {
+        let op = memory();
+        let fs = Fs::builder(op.clone())
+            .chunk_size(4096)
+            .verify_existing_content(true)
+            .build()
+            .await?;
+        let content = Buffer::from(noise(4096 * 3));
+        let mut writer = fs.new_file_writer("a");
+        writer.write(content.clone()).await?;
+        let chunks = writer.close().await?.file.chunks().to_vec();
+
+        // Existing chunks of the wrong size are overwritten.
+        op.write(&format!("data/{}", chunks[0]), "truncated")
+            .await?;
+        let mut writer = fs.new_streaming_writer("b");
+        writer.write(content.clone()).await?;
+        let result = writer.close().await?;
+        assert_eq!((result.new_chunks, result.dedup_chunks), (1, 2));
+        let stored = op.read(&format!("data/{}", chunks[0])).await?;
+        assert_eq!(stored.len(), 4097);
+
+        // Foreign content of the right size is rejected.
+        let foreign = [&[0][..], &noise(4096)].concat();
+        op.write(&format!("data/{}", chunks[1]), foreign).await?;
+        let mut writer = fs.new_streaming_writer("c");
+        let err = writer.write(content).await.unwrap_err();
+        assert!(err.to_string().contains("foreign content"), "{err}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_upload_leaves_no_objects() -> Result<()> {
+        let op = memory();
+        let store = MemoryChunkStore::default();
+        let fs = Fs::builder(op.clone())
+            .chunk_store(store.clone())
+            .chunk_size(4096)
+            .build()
+            .await?;
+        store.fail_puts(true);
+        let mut writer = fs.new_streaming_writer("a");
+        let err = writer.write(Buffer::from(noise(10_000))).await.unwrap_err();
+        assert!(err.to_string().contains("injected failure"), "{err}");
+        let err = writer.close().await.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(Error::Poisoned { .. })));
+        assert!(store.ids().is_empty());
+        assert!(op.list_with("/").recursive(true).await?.is_empty());
+
+        store.fail_puts(false);
+        let mut writer = fs.new_streaming_writer("a");
+        writer.write(Buffer::from(noise(10_000))).await?;
+        let file = writer.close().await?.file;
+        assert_eq!(store.ids().len(), file.chunks().len());
+        Ok(())
+    }
+}
@@ -0,0 +1,548 @@
+//! Helpers shared by unit tests.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use futures::lock::Mutex as AsyncMutex;
+use futures::stream::BoxStream;
+use futures::{stream, FutureExt, StreamExt};
+use opendal::raw::*;
+use opendal::{services, Buffer, ErrorKind, Metadata, Operator};
+
+use crate::ChunkStore;
+
+/// A fresh in-memory operator.
+pub(crate) fn memory() -> Operator {
+    Operator::new(services::Memory::default())
+        .expect("memory service must be available")
+        .finish()
+}
+
+/// Compressible text of `n` bytes.
+pub(crate) fn text(n: usize) -> Vec<u8> {
+    let words = ["epoch", "chunk", "manifest", "metadata", "commit", "file"];
+    let mut out = Vec::with_capacity(n);
+    let mut i = 0usize;
+    while out.len() < n {
+        out.extend_from_slice(words[i % words.len()].as_bytes());
+        out.push(if i.is_multiple_of(7) { b'\n' } else { b' ' });
+        i = i.wrapping_mul(31).wrapping_add(17) % 1009;
+    }
+    out.truncate(n);
+    out
+}
+
+/// Incompressible pseudo random bytes of `n` bytes.
+pub(crate) fn noise(n: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    (0..n)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// An in-memory chunk store counting calls, with failures injectable.
+///
+/// Clones share the same chunks and counters.
+#[derive(Clone, Default)]
+pub(crate) struct MemoryChunkStore {
+    chunks: Arc<Mutex<BTreeMap<String, Buffer>>>,
+    gets: Arc<AtomicUsize>,
+    pending_gets: Arc<AtomicUsize>,
+    max_pending_gets: Arc<AtomicUsize>,
+    puts: Arc<AtomicUsize>,
+    fail_puts: Arc<AtomicBool>,
+    corrupt_puts: Arc<Mutex<usize>>,
+}
+
+impl MemoryChunkStore {
+    /// The number of `get` calls so far.
+    pub(crate) fn gets(&self) -> usize {
+        self.gets.load(Ordering::SeqCst)
+    }
+
+    /// The max number of `get` calls pending at the same time so far.
+    pub(crate) fn max_pending_gets(&self) -> usize {
+        self.max_pending_gets.load(Ordering::SeqCst)
+    }
+
+    /// The number of successful `put` calls so far.
+    pub(crate) fn puts(&self) -> usize {
+        self.puts.load(Ordering::SeqCst)
+    }
+
+    /// Make every following `put` fail or not.
+    pub(crate) fn fail_puts(&self, fail: bool) {
+        self.fail_puts.store(fail, Ordering::SeqCst);
+    }
+
+    /// Corrupt the content of the next `n` puts.
+    pub(crate) fn corrupt_puts(&self, n: usize) {
+        *self.corrupt_puts.lock().unwrap() = n;
+    }
+
+    /// The ids of all stored chunks.
+    pub(crate) fn ids(&self) -> Vec<String> {
+        self.chunks.lock().unwrap().keys().cloned().collect()
+    }
+}
+
+impl ChunkStore for MemoryChunkStore {
+    fn put<'a>(&'a self, chunk_id: &'a str, buf: Buffer) -> BoxFuture<'a, Result<()>> {
+        if self.fail_puts.load(Ordering::SeqCst) {
+            let err = anyhow!("injected failure of putting chunk {chunk_id}");
+            return async move { Err(err) }.boxed();
+        }
+        let corrupt = {
+            let mut n = self.corrupt_puts.lock().unwrap();
+            let corrupt = *n > 0;
+            *n = n.saturating_sub(1);
+            corrupt
+        };
+        let buf = match corrupt {
+            true => {
+                let mut content = buf.to_vec();
+                let last = content.len() - 1;
+                content[last] ^= 0xff;
+                Buffer::from(content)
+            }
+            false => buf,
+        };
+        self.puts.fetch_add(1, Ordering::SeqCst);
+        self.chunks
+            .lock()
+            .unwrap()
+            .insert(chunk_id.to_string(), buf);
+        async move { Ok(()) }.boxed()
+    }
+
+    fn get<'a>(&'a self, chunk_id: &'a str) -> BoxFuture<'a, Result<Option<Buffer>>> {
+        self.gets.fetch_add(1, Ordering::SeqCst);
+        let pending = self.pending_gets.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_pending_gets.fetch_max(pending, Ordering::SeqCst);
+        let buf = self.chunks.lock().unwrap().get(chunk_id).cloned();
+        async move {
+            // Yield once, so that concurrent gets overlap.
+            tokio::task::yield_now().await;
+            self.pending_gets.fetch_sub(1, Ordering::SeqCst);
+            Ok(buf)
+        }
+        .boxed()
+    }
+
+    fn exists<'a>(&'a self, chunk_id: &'a str) -> BoxFuture<'a, Result<bool>> {
+        let exists = self.chunks.lock().unwrap().contains_key(chunk_id);
+        async move { Ok(exists) }.boxed()
+    }
+
+    fn size<'a>(&'a self, chunk_id: &'a str) -> BoxFuture<'a, Result<Option<u64>>> {
+        let size = self
+            .chunks
+            .lock()
+            .unwrap()
+            .get(chunk_id)
+            .map(|buf| buf.len() as u64);
+        async move { Ok(size) }.boxed()
+    }
+
+    fn delete<'a>(&'a self, chunk_id: &'a str) -> BoxFuture<'a, Result<()>> {
+        self.chunks.lock().unwrap().remove(chunk_id);
+        async move { Ok(()) }.boxed()
+    }
+
+    fn list(&self) -> BoxStream<'_, Result<String>> {
+        stream::iter(self.ids().into_iter().map(Ok)).boxed()
+    }
+}
+
+/// A fresh in-memory operator supporting conditional writes with etags.
+pub(crate) fn conditional_memory() -> Operator {
+    memory().layer(ConditionalLayer)
+}
+
+/// A layer emulating etags and conditional writes, which the memory service
+/// doesn't support.
+///
+/// Conditions are checked while closing writers, under a lock held until
+/// the content is written.
+struct ConditionalLayer;
+
+impl<A: Access> Layer<A> for ConditionalLayer {
+    type LayeredAccess = ConditionalAccessor<A>;
+
+    fn layer(&self, inner: A) -> Self::LayeredAccess {
+        ConditionalAccessor {
+            inner,
+            etags: Arc::default(),
+        }
+    }
+}
+
+/// The etags of every written path, and the counter to generate new ones.
+type Etags = Arc<AsyncMutex<(HashMap<String, String>, u64)>>;
+
+#[derive(Debug)]
+struct ConditionalAccessor<A> {
+    inner: A,
+    etags: Etags,
+}
+
+impl<A: Access> LayeredAccess for ConditionalAccessor<A> {
+    type Inner = A;
+    type Reader = A::Reader;
+    type Writer = ConditionalWriter<A::Writer>;
+    type Lister = A::Lister;
+    type Deleter = A::Deleter;
+    type BlockingReader = A::BlockingReader;
+    type BlockingWriter = A::BlockingWriter;
+    type BlockingLister = A::BlockingLister;
+    type BlockingDeleter = A::BlockingDeleter;
+
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+
+    fn info(&self) -> Arc<AccessorInfo> {
+        let inner = self.inner.info();
+        let info = AccessorInfo::default();
+        info.set_scheme(inner.scheme())
+            .set_root(&inner.root())
+            .set_name(&inner.name());
+        let mut cap = inner.full_capability();
+        cap.write_with_if_match = true;
+        cap.write_with_if_none_match = true;
+        cap.write_with_if_not_exists = true;
+        info.set_native_capability(cap);
+        Arc::new(info)
+    }
+
+    async fn read(&self, path: &str, args: OpRead) -> opendal::Result<(RpRead, Self::Reader)> {
+        self.inner.read(path, args).await
+    }
+
+    async fn write(&self, path: &str, args: OpWrite) -> opendal::Result<(RpWrite, Self::Writer)> {
+        let condition = (
+            args.if_match().map(str::to_string),
+            args.if_none_match().map(str::to_string),
+            args.if_not_exists(),
+        );
+        // The inner service rejects conditions, they're checked by us.
+        let args = match condition {
+            (None, None, false) => args,
+            _ => OpWrite::new(),
+        };
+        let (rp, inner) = self.inner.write(path, args).await?;
+        let writer = ConditionalWriter {
+            inner,
+            path: path.to_string(),
+            condition,
+            etags: self.etags.clone(),
+        };
+        Ok((rp, writer))
+    }
+
+    async fn stat(&self, path: &str, args: OpStat) -> opendal::Result<RpStat> {
+        let meta = self.inner.stat(path, args).await?.into_metadata();
+        let etags = self.etags.lock().await;
+        match etags.0.get(path) {
+            Some(etag) => Ok(RpStat::new(meta.with_etag(etag.clone()))),
+            None => Ok(RpStat::new(meta)),
+        }
+    }
+
+    async fn delete(&self) -> opendal::Result<(RpDelete, Self::Deleter)> {
+        self.inner.delete().await
+    }
+
+    async fn list(&self, path: &str, args: OpList) -> opendal::Result<(RpList, Self::Lister)> {
+        self.inner.list(path, args).await
+    }
+
+    fn blocking_read(
+        &self,
+        path: &str,
+        args: OpRead,
+    ) -> opendal::Result<(RpRead, Self::BlockingReader)> {
+        self.inner.blocking_read(path, args)
+    }
+
+    fn blocking_write(
+        &self,
+        path: &str,
+        args: OpWrite,
+    ) -> opendal::Result<(RpWrite, Self::BlockingWriter)> {
+        self.inner.blocking_write(path, args)
+    }
+
+    fn blocking_delete(&self) -> opendal::Result<(RpDelete, Self::BlockingDeleter)> {
+        self.inner.blocking_delete()
+    }
+
+    fn blocking_list(
+        &self,
+        path: &str,
+        args: OpList,
+    ) -> opendal::Result<(RpList, Self::BlockingLister)> {
+        self.inner.blocking_list(path, args)
+    }
+}
+
+struct ConditionalWriter<W> {
+    inner: W,
+    path: String,
+    /// The `if_match`, `if_none_match` and `if_not_exists` of the write.
+    condition: (Option<String>, Option<String>, bool),
+    etags: Etags,
+}
+
+impl<W: oio::Write> oio::Write for ConditionalWriter<W> {
+    async fn write(&mut self, bs: opendal::Buffer) -> opendal::Result<()> {
+        self.inner.write(bs).await
+    }
+
+    async fn close(&mut self) -> opendal::Result<Metadata> {
+        let mut etags = self.etags.lock().await;
+        let current = etags.0.get(&self.path);
+        let matched = match &self.condition {
+            (Some(tag), _, _) if tag == "*" => current.is_some(),
+            (Some(tag), _, _) => current == Some(tag),
+            (_, Some(tag), _) if tag == "*" => current.is_none(),
+            (_, Some(tag), _) => current != Some(tag),
+            (_, _, true) => current.is_none(),
+            _ => true,
+        };
+        if !matched {
+            self.inner.abort().await?;
+            return Err(opendal::Error::new(
+                ErrorKind::ConditionNotMatch,
+                "condition of write is not matched",
+            ));
+        }
+
+        let meta = self.inner.close().await?;
+        etags.1 += 1;
+        let etag = format!("\"{}\"", etags.1);
+        etags.0.insert(self.path.clone(), etag.clone());
+        Ok(meta.with_etag(etag))
+    }
+
+    async fn abort(&mut self) -> opendal::Result<()> {
+        self.inner.abort().await
+    }
+}
+
+/// A fresh in-memory operator without list support.
+pub(crate) fn unlistable_memory() -> Operator {
+    memory().layer(UnlistableLayer)
+}
+
+/// A layer rejecting every list, like services that can't list.
+struct UnlistableLayer;
+
+impl<A: Access> Layer<A> for UnlistableLayer {
+    type LayeredAccess = UnlistableAccessor<A>;
+
+    fn layer(&self, inner: A) -> Self::LayeredAccess {
+        UnlistableAccessor { inner }
+    }
+}
+
+#[derive(Debug)]
+struct UnlistableAccessor<A> {
+    inner: A,
+}
+
+impl<A: Access> LayeredAccess for UnlistableAccessor<A> {
+    type Inner = A;
+    type Reader = A::Reader;
+    type Writer = A::Writer;
+    type Lister = A::Lister;
+    type Deleter = A::Deleter;
+    type BlockingReader = A::BlockingReader;
+    type BlockingWriter = A::BlockingWriter;
+    type BlockingLister = A::BlockingLister;
+    type BlockingDeleter = A::BlockingDeleter;
+
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+
+    fn info(&self) -> Arc<AccessorInfo> {
+        let inner = self.inner.info();
+        let info = AccessorInfo::default();
+        info.set_scheme(inner.scheme())
+            .set_root(&inner.root())
+            .set_name(&inner.name());
+        let mut cap = inner.full_capability();
+        cap.list = false;
+        cap.list_with_recursive = false;
+        info.set_native_capability(cap);
+        Arc::new(info)
+    }
+
+    async fn read(&self, path: &str, args: OpRead) -> opendal::Result<(RpRead, Self::Reader)> {
+        self.inner.read(path, args).await
+    }
+
+    async fn write(&self, path: &str, args: OpWrite) -> opendal::Result<(RpWrite, Self::Writer)> {
+        self.inner.write(path, args).await
+    }
+
+    async fn stat(&self, path: &str, args: OpStat) -> opendal::Result<RpStat> {
+        self.inner.stat(path, args).await
+    }
+
+    async fn delete(&self) -> opendal::Result<(RpDelete, Self::Deleter)> {
+        self.inner.delete().await
+    }
+
+    async fn list(&self, _: &str, _: OpList) -> opendal::Result<(RpList, Self::Lister)> {
+        Err(opendal::Error::new(
+            ErrorKind::Unsupported,
+            "list is not supported",
+        ))
+    }
+
+    fn blocking_read(
+        &self,
+        path: &str,
+        args: OpRead,
+    ) -> opendal::Result<(RpRead, Self::BlockingReader)> {
+        self.inner.blocking_read(path, args)
+    }
+
+    fn blocking_write(
+        &self,
+        path: &str,
+        args: OpWrite,
+    ) -> opendal::Result<(RpWrite, Self::BlockingWriter)> {
+        self.inner.blocking_write(path, args)
+    }
+
+    fn blocking_delete(&self) -> opendal::Result<(RpDelete, Self::BlockingDeleter)> {
+        self.inner.blocking_delete()
+    }
+
+    fn blocking_list(&self, _: &str, _: OpList) -> opendal::Result<(RpList, Self::BlockingLister)> {
+        Err(opendal::Error::new(
+            ErrorKind::Unsupported,
+            "list is not supported",
+        ))
+    }
+}
+
+/// A fresh in-memory operator failing the first `failures` reads of `path`.
+pub(crate) fn flaky_memory(path: &str, failures: usize) -> Operator {
+    memory().layer(FlakyLayer {
+        path: path.to_string(),
+        failures: Arc::new(Mutex::new(failures)),
+    })
+}
+
+/// A layer failing reads of a path a given number of times, like transient
+/// network errors.
+struct FlakyLayer {
+    path: String,
+    failures: Arc<Mutex<usize>>,
+}
+
+impl<A: Access> Layer<A> for FlakyLayer {
+    type LayeredAccess = FlakyAccessor<A>;
+
+    fn layer(&self, inner: A) -> Self::LayeredAccess {
+        FlakyAccessor {
+            inner,
+            path: self.path.clone(),
+            failures: self.failures.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FlakyAccessor<A> {
+    inner: A,
+    path: String,
+    failures: Arc<Mutex<usize>>,
+}
+
+impl<A: Access> LayeredAccess for FlakyAccessor<A> {
+    type Inner = A;
+    type Reader = A::Reader;
+    type Writer = A::Writer;
+    type Lister = A::Lister;
+    type Deleter = A::Deleter;
+    type BlockingReader = A::BlockingReader;
+    type BlockingWriter = A::BlockingWriter;
+    type BlockingLister = A::BlockingLister;
+    type BlockingDeleter = A::BlockingDeleter;
+
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+
+    async fn read(&self, path: &str, args: OpRead) -> opendal::Result<(RpRead, Self::Reader)> {
+        let failed = path.trim_start_matches('/') == self.path && {
+            let mut n = self.failures.lock().unwrap();
+            let failed = *n > 0;
+            *n = n.saturating_sub(1);
+            failed
+        };
+        if failed {
+            return Err(
+                opendal::Error::new(ErrorKind::Unexpected, "injected read failure").set_temporary(),
+            );
+        }
+        self.inner.read(path, args).await
+    }
+
+    async fn write(&self, path: &str, args: OpWrite) -> opendal::Result<(RpWrite, Self::Writer)> {
+        self.inner.write(path, args).await
+    }
+
+    async fn stat(&self, path: &str, args: OpStat) -> opendal::Result<RpStat> {
+        self.inner.stat(path, args).await
+    }
+
+    async fn delete(&self) -> opendal::Result<(RpDelete, Self::Deleter)> {
+        self.inner.delete().await
+    }
+
+    async fn list(&self, path: &str, args: OpList) -> opendal::Result<(RpList, Self::Lister)> {
+        self.inner.list(path, args).await
+    }
+
+    fn blocking_read(
+        &self,
+        path: &str,
+        args: OpRead,
+    ) -> opendal::Result<(RpRead, Self::BlockingReader)> {
+        self.inner.blocking_read(path, args)
+    }
+
+    fn blocking_write(
+        &self,
+        path: &str,
+        args: OpWrite,
+    ) -> opendal::Result<(RpWrite, Self::BlockingWriter)> {
+        self.inner.blocking_write(path, args)
+    }
+
+    fn blocking_delete(&self) -> opendal::Result<(RpDelete, Self::BlockingDeleter)> {
+        self.inner.blocking_delete()
+    }
+
+    fn blocking_list(
+        &self,
+        path: &str,
+        args: OpList,
+    ) -> opendal::Result<(RpList, Self::BlockingLister)> {
+        self.inner.blocking_list(path, args)
+    }
+}
@@ -0,0 +1,59 @@
+use std::collections::BTreeMap;
+
+/// The report of verifying the file system.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// The error of reading or decoding the metadata.
+    pub metadata_error: Option<String>,
+    /// The error of reading, decoding or validating the manifest.
+    pub manifest_error: Option<String>,
+    /// Chunks that are missing from storage, grouped by file path.
+    pub missing_chunks: BTreeMap<String, Vec<String>>,
+    /// Chunks whose content doesn't match their id, grouped by file path.
+    pub mismatched_chunks: BTreeMap<String, Vec<String>>,
+}
+
+impl VerifyReport {
+    /// Returns `true` if no issue has been found.
+    pub fn is_ok(&self) -> bool {
+        self.metadata_error.is_none()
+            && self.manifest_error.is_none()
+            && self.missing_chunks.is_empty()
+            && self.mismatched_chunks.is_empty()
+    }
+}
+
+/// Options for `Fs::verify`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VerifyOptions {
+    pub(crate) deep: bool,
+    pub(crate) concurrency: usize,
+}
+
+impl Default for VerifyOptions {
+    fn default() -> Self {
+        Self {
+            deep: false,
+            concurrency: 4,
+        }
+    }
+}
+
+impl VerifyOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Download and rehash every chunk instead of only checking its
+    /// existence. Default to `false`.
+    pub fn deep(mut self, deep: bool) -> Self {
+        self.deep = deep;
+        self
+    }
+
+    /// Set the max number of chunks checked concurrently. Default to `4`.
+    pub fn concurrency(mut self, n: usize) -> Self {
+        self.concurrency = n.max(1);
+        self
+    }
+}